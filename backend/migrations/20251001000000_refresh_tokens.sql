@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+  id          TEXT PRIMARY KEY,           -- uuid v4
+  user_id     TEXT NOT NULL,
+  family_id   TEXT NOT NULL,              -- shared by every token rotated from the same login
+  token_hash  TEXT UNIQUE NOT NULL,       -- sha256 of the opaque token, never the token itself
+  expires_at  INTEGER NOT NULL,           -- unix seconds
+  rotated_at  INTEGER,                    -- set once exchanged for a new token (NULL = current)
+  revoked_at  INTEGER,                    -- set when the family is revoked (NULL = live)
+  created_at  INTEGER NOT NULL,           -- unix seconds
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_user ON refresh_tokens(user_id);
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_family ON refresh_tokens(family_id);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS revoked_tokens (
+  jti         TEXT PRIMARY KEY,           -- JWT id claim
+  user_id     TEXT NOT NULL,
+  expires_at  INTEGER NOT NULL,           -- token's own exp; row can be pruned after this
+  revoked_at  INTEGER NOT NULL            -- unix seconds
+);
+
+CREATE INDEX IF NOT EXISTS idx_revoked_tokens_expires ON revoked_tokens(expires_at);
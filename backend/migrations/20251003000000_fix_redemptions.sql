@@ -0,0 +1,22 @@
+-- redemptions.coupon_id was INTEGER, but coupons.id is a TEXT uuid.
+-- SQLite can't ALTER a column type, so rebuild the table.
+CREATE TABLE redemptions_new (
+  id          INTEGER PRIMARY KEY AUTOINCREMENT,
+  coupon_id   TEXT NOT NULL,
+  user_id     TEXT NOT NULL,
+  redeemed_at INTEGER NOT NULL,             -- unix seconds
+  UNIQUE(coupon_id),
+  FOREIGN KEY(coupon_id) REFERENCES coupons(id) ON DELETE CASCADE,
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+INSERT INTO redemptions_new(id,coupon_id,user_id,redeemed_at)
+  SELECT r.id, CAST(r.coupon_id AS TEXT), r.user_id, r.redeemed_at
+  FROM redemptions r
+  WHERE CAST(r.coupon_id AS TEXT) IN (SELECT id FROM coupons)
+    AND r.user_id IN (SELECT id FROM users);
+
+DROP TABLE redemptions;
+ALTER TABLE redemptions_new RENAME TO redemptions;
+
+CREATE INDEX IF NOT EXISTS idx_redemptions_user ON redemptions(user_id);
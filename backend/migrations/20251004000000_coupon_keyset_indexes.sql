@@ -0,0 +1,3 @@
+-- Keyset pagination orders by (created_at, id), optionally within one owner.
+CREATE INDEX IF NOT EXISTS idx_coupons_created ON coupons(created_at, id);
+CREATE INDEX IF NOT EXISTS idx_coupons_owner_created ON coupons(owner_id, created_at, id);
@@ -0,0 +1,3 @@
+-- Keyset pagination for the other sort keys, and service filtering.
+CREATE INDEX IF NOT EXISTS idx_coupons_expires_id ON coupons(expires_at, id);
+CREATE INDEX IF NOT EXISTS idx_coupons_service_created ON coupons(service, created_at, id);
@@ -0,0 +1,9 @@
+-- Machine-readable discount terms. Amounts are decimal strings to keep them exact.
+-- NULL discount_kind = legacy coupon with only a free-text description.
+ALTER TABLE coupons ADD COLUMN discount_kind TEXT
+  CHECK (discount_kind IN ('percentage','fixed_amount','free_shipping','free_item'));
+ALTER TABLE coupons ADD COLUMN discount_value TEXT;        -- percent, amount or free-item quantity
+ALTER TABLE coupons ADD COLUMN discount_currency TEXT;     -- ISO 4217
+ALTER TABLE coupons ADD COLUMN discount_sku TEXT;          -- free_item only
+ALTER TABLE coupons ADD COLUMN min_order_amount TEXT;
+ALTER TABLE coupons ADD COLUMN max_discount_amount TEXT;
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS coupon_batches (
+  id          TEXT PRIMARY KEY,           -- uuid v4
+  pattern     TEXT NOT NULL,              -- e.g. SUMMER-XXXX-XXXX
+  count       INTEGER NOT NULL,
+  description TEXT NOT NULL DEFAULT '',
+  service     TEXT NOT NULL DEFAULT '',
+  expires_at  INTEGER NOT NULL,           -- unix seconds
+  created_by  TEXT,                       -- admin user id
+  created_at  INTEGER NOT NULL,           -- unix seconds
+  FOREIGN KEY(created_by) REFERENCES users(id) ON DELETE SET NULL
+);
+
+ALTER TABLE coupons ADD COLUMN batch_id TEXT REFERENCES coupon_batches(id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS idx_coupons_batch ON coupons(batch_id, created_at, id);
@@ -0,0 +1,28 @@
+-- Redemption caps; NULL = unlimited. The defaults keep existing coupons single-use.
+ALTER TABLE coupons ADD COLUMN max_redemptions INTEGER DEFAULT 1
+  CHECK (max_redemptions IS NULL OR max_redemptions > 0);
+ALTER TABLE coupons ADD COLUMN max_redemptions_per_user INTEGER DEFAULT 1
+  CHECK (max_redemptions_per_user IS NULL OR max_redemptions_per_user > 0);
+-- Denormalised COUNT(*) of redemptions, bumped in the same statement that checks the cap
+ALTER TABLE coupons ADD COLUMN redemption_count INTEGER NOT NULL DEFAULT 0;
+
+UPDATE coupons SET redemption_count = (SELECT COUNT(*) FROM redemptions r WHERE r.coupon_id = coupons.id);
+
+-- A coupon can now be redeemed more than once, so drop UNIQUE(coupon_id).
+CREATE TABLE redemptions_new (
+  id          INTEGER PRIMARY KEY AUTOINCREMENT,
+  coupon_id   TEXT NOT NULL,
+  user_id     TEXT NOT NULL,
+  redeemed_at INTEGER NOT NULL,             -- unix seconds
+  FOREIGN KEY(coupon_id) REFERENCES coupons(id) ON DELETE CASCADE,
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+INSERT INTO redemptions_new(id,coupon_id,user_id,redeemed_at)
+  SELECT id, coupon_id, user_id, redeemed_at FROM redemptions;
+
+DROP TABLE redemptions;
+ALTER TABLE redemptions_new RENAME TO redemptions;
+
+CREATE INDEX IF NOT EXISTS idx_redemptions_user ON redemptions(user_id);
+CREATE INDEX IF NOT EXISTS idx_redemptions_coupon_user ON redemptions(coupon_id, user_id);
@@ -0,0 +1,26 @@
+-- Who holds which coupon. Replaces coupons.owner_id so one public code can have many holders.
+CREATE TABLE IF NOT EXISTS coupon_claims (
+  coupon_id  TEXT NOT NULL,
+  user_id    TEXT NOT NULL,
+  claimed_at INTEGER NOT NULL,              -- unix seconds
+  PRIMARY KEY(coupon_id, user_id),          -- each user holds a coupon at most once
+  FOREIGN KEY(coupon_id) REFERENCES coupons(id) ON DELETE CASCADE,
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- myCoupons
+CREATE INDEX IF NOT EXISTS idx_coupon_claims_user ON coupon_claims(user_id, coupon_id);
+
+INSERT INTO coupon_claims(coupon_id,user_id,claimed_at)
+  SELECT id, owner_id, CAST(strftime('%s','now') AS INTEGER) FROM coupons
+  WHERE owner_id IS NOT NULL AND owner_id IN (SELECT id FROM users);
+
+-- How many users may hold the coupon at once; NULL = unlimited. 1 keeps existing coupons single-holder.
+ALTER TABLE coupons ADD COLUMN max_claims INTEGER DEFAULT 1
+  CHECK (max_claims IS NULL OR max_claims > 0);
+
+-- owner_id is no longer read. SQLite can only drop a foreign-key column by rebuilding coupons,
+-- which would cascade into redemptions, so it stays behind, always NULL.
+UPDATE coupons SET owner_id = NULL;
+DROP INDEX IF EXISTS idx_coupons_owner;
+DROP INDEX IF EXISTS idx_coupons_owner_created;
@@ -0,0 +1,6 @@
+-- Start of the validity window (unix seconds); coupons are usable from valid_from until expires_at.
+ALTER TABLE coupons ADD COLUMN valid_from INTEGER NOT NULL DEFAULT 0;
+UPDATE coupons SET valid_from = created_at;
+
+ALTER TABLE coupon_batches ADD COLUMN valid_from INTEGER NOT NULL DEFAULT 0;
+UPDATE coupon_batches SET valid_from = created_at;
@@ -0,0 +1,5 @@
+-- Deleting a coupon archives it; purging removes the row for good.
+ALTER TABLE coupons ADD COLUMN deleted_at INTEGER;
+ALTER TABLE coupons ADD COLUMN deleted_by TEXT REFERENCES users(id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS idx_coupons_deleted ON coupons(deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,27 @@
+-- Append-only record of privileged and ownership-changing operations.
+-- No foreign keys: entries must outlive the users and coupons they mention.
+CREATE TABLE IF NOT EXISTS audit_events (
+  id          INTEGER PRIMARY KEY AUTOINCREMENT,
+  actor_id    TEXT,              -- NULL for anonymous actions (registration)
+  action      TEXT NOT NULL,     -- e.g. coupon.update
+  target_type TEXT NOT NULL,     -- coupon | coupon_batch | user
+  target_id   TEXT NOT NULL,
+  before_json TEXT,
+  after_json  TEXT,
+  ip          TEXT,
+  created_at  INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_actor  ON audit_events(actor_id, id);
+CREATE INDEX IF NOT EXISTS idx_audit_target ON audit_events(target_type, target_id, id);
+CREATE INDEX IF NOT EXISTS idx_audit_action ON audit_events(action, id);
+
+CREATE TRIGGER IF NOT EXISTS audit_events_no_update BEFORE UPDATE ON audit_events
+BEGIN
+  SELECT RAISE(ABORT, 'audit_events is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS audit_events_no_delete BEFORE DELETE ON audit_events
+BEGIN
+  SELECT RAISE(ABORT, 'audit_events is append-only');
+END;
@@ -0,0 +1,49 @@
+-- Named roles bundle fine-grained permissions; users can hold several roles.
+CREATE TABLE IF NOT EXISTS roles (
+  name        TEXT PRIMARY KEY,
+  description TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS role_permissions (
+  role       TEXT NOT NULL REFERENCES roles(name) ON DELETE CASCADE,
+  permission TEXT NOT NULL,  -- e.g. coupon:create
+  PRIMARY KEY (role, permission)
+);
+
+CREATE TABLE IF NOT EXISTS user_roles (
+  user_id    TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  role       TEXT NOT NULL REFERENCES roles(name) ON DELETE CASCADE,
+  granted_at INTEGER NOT NULL,  -- unix seconds
+  PRIMARY KEY (user_id, role)
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_roles_role ON user_roles(role);
+
+INSERT INTO roles(name, description) VALUES
+  ('superadmin',       'Everything, including role management and purging'),
+  ('campaign_manager', 'Creates, edits and archives coupons'),
+  ('support',          'Looks up and fixes individual coupons'),
+  ('auditor',          'Read-only access to coupons and the audit log');
+
+INSERT INTO role_permissions(role, permission) VALUES
+  ('superadmin', 'coupon:read'),
+  ('superadmin', 'coupon:create'),
+  ('superadmin', 'coupon:update'),
+  ('superadmin', 'coupon:delete'),
+  ('superadmin', 'coupon:purge'),
+  ('superadmin', 'user:manage'),
+  ('superadmin', 'audit:read'),
+  ('campaign_manager', 'coupon:read'),
+  ('campaign_manager', 'coupon:create'),
+  ('campaign_manager', 'coupon:update'),
+  ('campaign_manager', 'coupon:delete'),
+  ('support', 'coupon:read'),
+  ('support', 'coupon:update'),
+  ('auditor', 'coupon:read'),
+  ('auditor', 'audit:read');
+
+-- Existing admins keep full access.
+INSERT INTO user_roles(user_id, role, granted_at)
+  SELECT id, 'superadmin', created_at FROM users WHERE is_admin = 1;
+
+ALTER TABLE users DROP COLUMN is_admin;
@@ -0,0 +1,3 @@
+-- Suspended accounts can't log in or use existing tokens.
+ALTER TABLE users ADD COLUMN suspended_at INTEGER;
+ALTER TABLE users ADD COLUMN suspended_reason TEXT;
@@ -0,0 +1,15 @@
+-- Single-use password reset tokens. Only a hash of the emailed token is stored.
+CREATE TABLE IF NOT EXISTS password_resets (
+  token_hash  TEXT PRIMARY KEY,           -- sha256 of the opaque token
+  user_id     TEXT NOT NULL,
+  expires_at  INTEGER NOT NULL,           -- unix seconds
+  used_at     INTEGER,                    -- set once redeemed (NULL = unused)
+  created_at  INTEGER NOT NULL,           -- unix seconds
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_password_resets_user ON password_resets(user_id);
+CREATE INDEX IF NOT EXISTS idx_password_resets_expires ON password_resets(expires_at);
+
+-- Access tokens issued before this (unix seconds) are refused, e.g. after a password reset.
+ALTER TABLE users ADD COLUMN sessions_valid_after INTEGER;
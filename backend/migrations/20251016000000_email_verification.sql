@@ -0,0 +1,16 @@
+-- Proof that the user owns their email address. Existing accounts count as verified.
+ALTER TABLE users ADD COLUMN email_verified_at INTEGER;
+UPDATE users SET email_verified_at = created_at;
+
+-- Single-use verification tokens. Only a hash of the emailed token is stored.
+CREATE TABLE IF NOT EXISTS email_verifications (
+  token_hash  TEXT PRIMARY KEY,           -- sha256 of the opaque token
+  user_id     TEXT NOT NULL,
+  expires_at  INTEGER NOT NULL,           -- unix seconds
+  used_at     INTEGER,                    -- set once redeemed (NULL = unused)
+  created_at  INTEGER NOT NULL,           -- unix seconds
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_email_verifications_user ON email_verifications(user_id);
+CREATE INDEX IF NOT EXISTS idx_email_verifications_expires ON email_verifications(expires_at);
@@ -0,0 +1,11 @@
+-- Failed login attempts, per account (lower-cased email, known or not) and per client IP.
+CREATE TABLE IF NOT EXISTS login_failures (
+  scope            TEXT NOT NULL,             -- 'account' or 'ip'
+  subject          TEXT NOT NULL,             -- the email or the IP address
+  failures         INTEGER NOT NULL,          -- consecutive failures within the window
+  last_failure_at  INTEGER NOT NULL,          -- unix seconds
+  locked_until     INTEGER,                   -- unix seconds; no logins before this
+  PRIMARY KEY (scope, subject)
+);
+
+CREATE INDEX IF NOT EXISTS idx_login_failures_last ON login_failures(last_failure_at);
@@ -0,0 +1,10 @@
+-- Emails are stored trimmed and lower-cased from now on. Accounts whose addresses differ
+-- only in case or surrounding spaces can't both keep theirs, so the index below stops this
+-- migration (UNIQUE constraint failed: index 'users_email_canonical') before anything
+-- changes. List them with
+--   SELECT lower(trim(email)), group_concat(id || ' ' || email, ', ')
+--   FROM users GROUP BY lower(trim(email)) HAVING count(*) > 1;
+-- merge or rename them, then run the migration again. The index keeps it that way.
+CREATE UNIQUE INDEX users_email_canonical ON users(lower(trim(email)));
+
+UPDATE users SET email = lower(trim(email)) WHERE email <> lower(trim(email));
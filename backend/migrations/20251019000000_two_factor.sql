@@ -0,0 +1,29 @@
+-- TOTP two-factor authentication. The secret is stored from enrollment on; it only
+-- counts once confirmed with a first code (totp_enabled_at set).
+ALTER TABLE users ADD COLUMN totp_secret TEXT;           -- base32, as shown to the authenticator app
+ALTER TABLE users ADD COLUMN totp_enabled_at INTEGER;    -- unix seconds; NULL = not enrolled
+ALTER TABLE users ADD COLUMN totp_last_step INTEGER;     -- last accepted time step, so a code works once
+
+-- One-time recovery codes, argon2-hashed like passwords.
+CREATE TABLE IF NOT EXISTS recovery_codes (
+  id          TEXT PRIMARY KEY,
+  user_id     TEXT NOT NULL,
+  code_hash   TEXT NOT NULL,              -- argon2 PHC string
+  used_at     INTEGER,                    -- set once redeemed (NULL = unused)
+  created_at  INTEGER NOT NULL,           -- unix seconds
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_recovery_codes_user ON recovery_codes(user_id);
+
+-- Pending logins: the password was right, a second factor is still owed.
+CREATE TABLE IF NOT EXISTS mfa_challenges (
+  token_hash  TEXT PRIMARY KEY,           -- sha256 of the opaque token
+  user_id     TEXT NOT NULL,
+  expires_at  INTEGER NOT NULL,           -- unix seconds
+  attempts    INTEGER NOT NULL DEFAULT 0, -- wrong codes so far
+  created_at  INTEGER NOT NULL,           -- unix seconds
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_mfa_challenges_expires ON mfa_challenges(expires_at);
@@ -0,0 +1,28 @@
+-- Merchant API keys for service-to-service calls. Only a hash of each key is stored;
+-- the key itself is shown once, when it's created.
+CREATE TABLE IF NOT EXISTS api_keys (
+  id           TEXT PRIMARY KEY,           -- uuid v4
+  name         TEXT NOT NULL,              -- what it's for, e.g. "my-store checkout"
+  key_hash     TEXT NOT NULL UNIQUE,       -- sha256 of the key
+  key_prefix   TEXT NOT NULL,              -- first characters of the key, to tell keys apart
+  created_by   TEXT REFERENCES users(id) ON DELETE SET NULL,
+  created_at   INTEGER NOT NULL,           -- unix seconds
+  last_used_at INTEGER,                    -- unix seconds, updated at most once a minute
+  revoked_at   INTEGER                     -- set once revoked (NULL = usable)
+);
+
+-- The services whose coupons a key can reach (at least one).
+CREATE TABLE IF NOT EXISTS api_key_services (
+  api_key_id TEXT NOT NULL REFERENCES api_keys(id) ON DELETE CASCADE,
+  service    TEXT NOT NULL,
+  PRIMARY KEY (api_key_id, service)
+);
+
+CREATE TABLE IF NOT EXISTS api_key_permissions (
+  api_key_id TEXT NOT NULL REFERENCES api_keys(id) ON DELETE CASCADE,
+  permission TEXT NOT NULL,  -- e.g. coupon:create
+  PRIMARY KEY (api_key_id, permission)
+);
+
+-- Changes made with an API key record the key here; actor_id stays NULL for them.
+ALTER TABLE audit_events ADD COLUMN api_key_id TEXT;
@@ -0,0 +1,3 @@
+-- sessions_valid_after moves to milliseconds, compared with the access token's iat_ms,
+-- so a token issued in the same second as a password reset no longer survives it.
+UPDATE users SET sessions_valid_after = sessions_valid_after * 1000 WHERE sessions_valid_after IS NOT NULL;
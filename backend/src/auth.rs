@@ -2,13 +2,34 @@ use anyhow::Result;
 use argon2::{Argon2, PasswordHasher, PasswordVerifier, password_hash::{SaltString, PasswordHash}};
 use jsonwebtoken::{encode, decode, Header, Validation, EncodingKey, DecodingKey, Algorithm};
 use serde::{Serialize, Deserialize};
-use rand_core::OsRng; // <- not rand::rngs::OsRng
+use rand_core::{OsRng, RngCore}; // <- not rand::rngs::OsRng
+use sha2::{Digest, Sha256};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use sha1::Sha1;
+use sqlx::SqlitePool;
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+use crate::db;
+use crate::error::ApiError;
+
+pub const REFRESH_TOKEN_TTL_SECS: i64 = 30 * 24 * 60 * 60; // 30 days
+pub const PASSWORD_RESET_TTL_SECS: i64 = 60 * 60; // 1 hour
+pub const EMAIL_VERIFICATION_TTL_SECS: i64 = 48 * 60 * 60; // 2 days
 
 
 #[derive(Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    #[serde(default)]
+    pub iat: usize,  // issued at
+    #[serde(default)]
+    pub iat_ms: i64, // issued at, in ms; tokens from before a password reset or suspension are refused
+    pub jti: String, // unique per token, used for revocation
 }
 
 pub fn hash_password(plain: &str) -> Result<String> {
@@ -26,18 +47,288 @@ pub fn verify_password(hash: &str, plain: &str) -> bool {
         .is_ok()
 }
 
+// Argon2 is deliberately expensive: at most this many run at once, on the blocking pool,
+// so a flood of logins queues up instead of starving the async workers.
+static PASSWORD_CHECKS: Semaphore = Semaphore::const_new(4);
+
+pub async fn verify_password_throttled(hash: String, plain: String) -> Result<bool> {
+    let _permit = PASSWORD_CHECKS.acquire().await?;
+    Ok(tokio::task::spawn_blocking(move || verify_password(&hash, &plain)).await?)
+}
+
+// Hashing costs the same as checking, so it queues behind the same permits.
+pub async fn hash_password_throttled(plain: String) -> Result<String> {
+    let _permit = PASSWORD_CHECKS.acquire().await?;
+    tokio::task::spawn_blocking(move || hash_password(&plain)).await?
+}
+
+// ---------- Registration input ----------
+
+const MAX_EMAIL_LEN: usize = 254;
+
+/// How emails are stored and looked up: trimmed and lower-cased.
+pub fn canonical_email(raw: &str) -> String {
+    raw.trim().to_lowercase()
+}
+
+/// `canonical_email`, rejecting anything that isn't a plausible address.
+pub fn normalize_email(raw: &str) -> Result<String, ApiError> {
+    let email = canonical_email(raw);
+    if email.len() > MAX_EMAIL_LEN || email.parse::<lettre::Address>().is_err() {
+        return Err(ApiError::validation("Enter a valid email address"));
+    }
+    Ok(email)
+}
+
+// Argon2 hashes whatever it is given; an upper bound keeps that cheap.
+const MAX_PASSWORD_LEN: usize = 128;
+
+#[derive(Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// How many of lower case, upper case, digits and symbols must appear
+    pub min_classes: usize,
+}
+
+impl PasswordPolicy {
+    /// PASSWORD_MIN_LENGTH (default 8) and PASSWORD_MIN_CLASSES (default 2).
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: usize| {
+            std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+        };
+        PasswordPolicy {
+            min_length: var("PASSWORD_MIN_LENGTH", 8).min(MAX_PASSWORD_LEN),
+            min_classes: var("PASSWORD_MIN_CLASSES", 2).min(4),
+        }
+    }
+
+    /// `email`: the account's (normalized) address, which the password may not contain.
+    pub fn check(&self, password: &str, email: &str) -> Result<(), ApiError> {
+        let len = password.chars().count();
+        if len < self.min_length {
+            return Err(ApiError::validation(format!(
+                "Password must be at least {} characters long",
+                self.min_length
+            )));
+        }
+        if len > MAX_PASSWORD_LEN {
+            return Err(ApiError::validation(format!(
+                "Password must be at most {MAX_PASSWORD_LEN} characters long"
+            )));
+        }
+        let classes = [
+            password.chars().any(|c| c.is_lowercase()),
+            password.chars().any(|c| c.is_uppercase()),
+            password.chars().any(|c| c.is_numeric()),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|&&has| has).count() < self.min_classes {
+            return Err(ApiError::validation(format!(
+                "Password must mix at least {} of: lower case, upper case, digits, symbols",
+                self.min_classes
+            )));
+        }
+        let local = email.split('@').next().unwrap_or_default();
+        if local.chars().count() >= 3 && password.to_lowercase().contains(local) {
+            return Err(ApiError::validation("Password must not contain your email address"));
+        }
+        Ok(())
+    }
+}
+
+// ---------- Login throttling ----------
+
+pub struct LoginThrottle {
+    pub scope: &'static str,
+    pub free_attempts: i64,  // failures allowed before the first lockout
+    pub base_lock_secs: i64, // doubles with every further failure
+    pub max_lock_secs: i64,
+}
+
+pub const ACCOUNT_THROTTLE: LoginThrottle =
+    LoginThrottle { scope: "account", free_attempts: 5, base_lock_secs: 30, max_lock_secs: 15 * 60 };
+// Looser, since many people can share an address (offices, NAT).
+pub const IP_THROTTLE: LoginThrottle =
+    LoginThrottle { scope: "ip", free_attempts: 20, base_lock_secs: 30, max_lock_secs: 15 * 60 };
+// Failures older than this are forgotten.
+pub const LOGIN_FAILURE_WINDOW_SECS: i64 = 24 * 60 * 60;
+
 pub fn make_jwt_3min(secret: &str, user_id: &str) -> Result<String> {
-    let exp = (chrono::Utc::now().timestamp() + 180) as usize; // 3 minutes
-    let claims = Claims { sub: user_id.to_string(), exp };
+    let iat_ms = chrono::Utc::now().timestamp_millis();
+    let iat = (iat_ms / 1000) as usize;
+    let exp = iat + 180; // 3 minutes
+    let claims = Claims { sub: user_id.to_string(), exp, iat, iat_ms, jti: Uuid::new_v4().to_string() };
     Ok(encode(&Header::new(Algorithm::HS256), &claims,
               &EncodingKey::from_secret(secret.as_bytes()))?)
 }
 
-pub fn parse_jwt(secret: &str, token: &str) -> Result<String> {
+/// Signature + expiry check only. Use `parse_jwt` to also honour revocation.
+pub fn decode_jwt(secret: &str, token: &str) -> Result<Claims> {
     let data = decode::<Claims>(
         token,
         &DecodingKey::from_secret(secret.as_bytes()),
         &Validation::new(Algorithm::HS256),
-    )?;
-    Ok(data.claims.sub)
+    )
+    .map_err(|e| ApiError::unauthenticated(format!("invalid token: {e}")))?;
+    Ok(data.claims)
+}
+
+pub async fn parse_jwt(pool: &SqlitePool, secret: &str, token: &str) -> Result<String> {
+    let claims = decode_jwt(secret, token)?;
+    if db::is_token_revoked(pool, &claims.jti).await? {
+        anyhow::bail!(ApiError::unauthenticated("token has been revoked"));
+    }
+    // Tokens from before iat_ms existed only have whole seconds.
+    let issued_at_ms = if claims.iat_ms > 0 { claims.iat_ms } else { claims.iat as i64 * 1000 };
+    if !db::is_session_active(pool, &claims.sub, issued_at_ms).await? {
+        anyhow::bail!(ApiError::unauthenticated("session has ended (account suspended or deleted, or password reset)"));
+    }
+    Ok(claims.sub)
+}
+
+// ---------- API keys ----------
+
+/// Request header carrying a merchant API key (instead of `Authorization: Bearer`).
+pub const API_KEY_HEADER: &str = "x-api-key";
+const API_KEY_PREFIX: &str = "ck_";
+pub const API_KEY_PREFIX_LEN: usize = 10; // stored in clear, to tell keys apart
+
+pub fn make_api_key() -> String {
+    format!("{API_KEY_PREFIX}{}", make_opaque_token())
+}
+
+/// The key's record if it exists and isn't revoked; also notes that it was used.
+pub async fn parse_api_key(pool: &SqlitePool, key: &str) -> Result<db::DbApiKey> {
+    let Some(k) = db::api_key_by_hash(pool, &hash_opaque_token(key)).await? else {
+        anyhow::bail!(ApiError::unauthenticated("Unauthorized: invalid or revoked API key"));
+    };
+    db::touch_api_key(pool, &k.id).await?;
+    Ok(k)
+}
+
+/// Opaque, random token (refresh tokens, password resets). Only its hash is stored.
+pub fn make_opaque_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Opaque tokens are high-entropy, so a fast digest is enough (and lets us look them up).
+pub fn hash_opaque_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+// ---------- Two-factor authentication (TOTP, RFC 6238) ----------
+
+pub const MFA_CHALLENGE_TTL_SECS: i64 = 5 * 60; // between the password and the code
+pub const MFA_MAX_ATTEMPTS: i64 = 5; // wrong codes before a pending login is dropped
+pub const RECOVERY_CODE_COUNT: usize = 10;
+pub const RECOVERY_CODE_LEN: usize = 10; // characters, not counting the dash
+pub const TOTP_ISSUER: &str = "Coupons"; // shown next to the account in authenticator apps
+const TOTP_STEP_SECS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+const TOTP_SKEW_STEPS: i64 = 1; // also accept the neighbouring codes, for clock drift
+
+/// 160 random bits, base32 like authenticator apps expect.
+pub fn make_totp_secret() -> String {
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+    BASE32_NOPAD.encode(&bytes)
+}
+
+/// What authenticator apps read from the enrollment QR code.
+pub fn totp_uri(account: &str, secret: &str) -> String {
+    let issuer = utf8_percent_encode(TOTP_ISSUER, NON_ALPHANUMERIC);
+    let account = utf8_percent_encode(account, NON_ALPHANUMERIC);
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}\
+         &algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP_SECS}"
+    )
+}
+
+fn totp_at(key: &[u8], step: i64) -> u32 {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    Mac::update(&mut mac, &step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[19] & 0x0f) as usize;
+    let bin = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    (bin & 0x7fff_ffff) % 10u32.pow(TOTP_DIGITS)
+}
+
+/// The time step `code` is valid for, if that's now (give or take a step) and later than
+/// `after`, the last step already used.
+pub fn check_totp(secret: &str, code: &str, now: i64, after: Option<i64>) -> Option<i64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != TOTP_DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let key = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let current = now.div_euclid(TOTP_STEP_SECS);
+    (current - TOTP_SKEW_STEPS..=current + TOTP_SKEW_STEPS)
+        .filter(|&step| after.is_none_or(|last| step > last))
+        .find(|&step| totp_at(&key, step) == code)
+}
+
+/// Fresh recovery codes like `k3fq7-m2xzp`, shown to the user once.
+pub fn make_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 7];
+            OsRng.fill_bytes(&mut bytes);
+            let chars = BASE32_NOPAD.encode(&bytes).to_lowercase();
+            format!("{}-{}", &chars[..RECOVERY_CODE_LEN / 2], &chars[RECOVERY_CODE_LEN / 2..RECOVERY_CODE_LEN])
+        })
+        .collect()
+}
+
+/// What gets hashed and compared: the code without dashes, spaces or case.
+pub fn normalize_recovery_code(raw: &str) -> String {
+    raw.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B, SHA-1 key; the RFC's 8-digit codes cut down to our 6.
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn totp_matches_rfc_6238_vectors() {
+        for (t, code) in [
+            (59, 287_082),
+            (1_111_111_109, 81_804),
+            (1_111_111_111, 50_471),
+            (1_234_567_890, 5_924),
+            (2_000_000_000, 279_037),
+            (20_000_000_000, 353_130),
+        ] {
+            assert_eq!(totp_at(RFC_KEY, t / TOTP_STEP_SECS), code, "T={t}");
+        }
+    }
+
+    #[test]
+    fn check_totp_allows_one_step_of_skew_and_no_reuse() {
+        let secret = BASE32_NOPAD.encode(RFC_KEY);
+        let now = 1_111_111_111; // step 37037037; 081804 is the step before
+        assert_eq!(check_totp(&secret, "050 471", now, None), Some(37_037_037));
+        assert_eq!(check_totp(&secret, "081804", now, None), Some(37_037_036));
+        assert_eq!(check_totp(&secret, "081804", now, Some(37_037_036)), None);
+        assert_eq!(check_totp(&secret, "050471", now, Some(37_037_036)), Some(37_037_037));
+        assert_eq!(check_totp(&secret, "005924", now, None), None); // another day's code
+        assert_eq!(check_totp(&secret, "50471", now, None), None);
+    }
+
+    #[test]
+    fn recovery_codes_match_however_typed() {
+        let codes = make_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        let code = normalize_recovery_code(&codes[0]);
+        assert_eq!(code.len(), RECOVERY_CODE_LEN);
+        let typed = format!(" {} ", codes[0].to_uppercase().replace('-', ""));
+        let hash = hash_password(&code).unwrap();
+        assert!(verify_password(&hash, &normalize_recovery_code(&typed)));
+        assert!(!verify_password(&hash, &normalize_recovery_code(&codes[1])));
+    }
 }
@@ -0,0 +1,106 @@
+use crate::error::ApiError;
+
+// ---------- Permissions ----------
+
+// What a resolver needs. Roles bundle these in the role_permissions table;
+// a user has a permission if any of their roles grants it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    CouponRead,   // every coupon: archived, held by others, batches, CSV export
+    CouponCreate, // createCoupon, generateCoupons, CSV import
+    CouponUpdate, // updateCoupon
+    CouponDelete, // deleteCoupon, restoreCoupon
+    CouponPurge,  // purgeCoupon
+    UserManage,   // assign and revoke roles
+    AuditRead,    // auditLog
+}
+
+impl Permission {
+    pub const ALL: [Permission; 7] = [
+        Permission::CouponRead,
+        Permission::CouponCreate,
+        Permission::CouponUpdate,
+        Permission::CouponDelete,
+        Permission::CouponPurge,
+        Permission::UserManage,
+        Permission::AuditRead,
+    ];
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Permission::ALL.into_iter().find(|p| p.as_str() == s)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::CouponRead => "coupon:read",
+            Permission::CouponCreate => "coupon:create",
+            Permission::CouponUpdate => "coupon:update",
+            Permission::CouponDelete => "coupon:delete",
+            Permission::CouponPurge => "coupon:purge",
+            Permission::UserManage => "user:manage",
+            Permission::AuditRead => "audit:read",
+        }
+    }
+}
+
+// Holds every permission; the first holder is set up with bootstrapAdmin or grant-superadmin, and the last can't lose it.
+pub const SUPERADMIN: &str = "superadmin";
+
+// What an API key can be given: coupon work only, never user management or the
+// (service-wide) audit log.
+pub const API_KEY_PERMISSIONS: [Permission; 5] = [
+    Permission::CouponRead,
+    Permission::CouponCreate,
+    Permission::CouponUpdate,
+    Permission::CouponDelete,
+    Permission::CouponPurge,
+];
+
+// ---------- Principals ----------
+
+/// Who a staff request acts for: a signed-in user, or a merchant API key.
+#[derive(Clone, Debug)]
+pub enum Principal {
+    User(String),
+    /// Only reaches coupons of its services
+    ApiKey { id: String, services: Vec<String> },
+}
+
+impl Principal {
+    pub fn user_id(&self) -> Option<&str> {
+        match self {
+            Principal::User(id) => Some(id),
+            Principal::ApiKey { .. } => None,
+        }
+    }
+
+    pub fn api_key_id(&self) -> Option<&str> {
+        match self {
+            Principal::User(_) => None,
+            Principal::ApiKey { id, .. } => Some(id),
+        }
+    }
+
+    /// Errors unless coupons of `service` are within reach.
+    pub fn check_service(&self, service: &str) -> Result<(), ApiError> {
+        match self {
+            Principal::ApiKey { services, .. } if !services.iter().any(|s| s == service) => Err(
+                ApiError::forbidden(format!("Forbidden: this API key can't access service {service:?}")),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Narrows a listing's service filter (empty = all) to what's within reach.
+    pub fn scope_services(&self, requested: Vec<String>) -> Result<Vec<String>, ApiError> {
+        match self {
+            Principal::ApiKey { services, .. } if requested.is_empty() => Ok(services.clone()),
+            _ => {
+                for s in &requested {
+                    self.check_service(s)?;
+                }
+                Ok(requested)
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::{bail, Result};
+use rand_core::{OsRng, RngCore};
+
+// ---------- Coupon code patterns ----------
+
+// No 0/O or 1/I, so codes survive being read aloud or retyped.
+pub const DEFAULT_ALPHABET: &str = "ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+pub const MAX_CODE_LEN: usize = 64;
+// Possible codes per code requested; keeps the pattern space mostly empty so that
+// each draw collides with an existing code less than 1% of the time.
+const HEADROOM: f64 = 100.0;
+
+enum Part {
+    Literal(char),
+    Random,
+}
+
+// A pattern like `SUMMER-XXXX-XXXX`: every `X` becomes a random alphabet character,
+// `\X` is a literal X, anything else is copied as is.
+pub struct CodePattern {
+    parts: Vec<Part>,
+    alphabet: Vec<char>,
+}
+
+impl CodePattern {
+    pub fn parse(pattern: &str, alphabet: Option<&str>) -> Result<Self> {
+        let mut alphabet: Vec<char> = alphabet.unwrap_or(DEFAULT_ALPHABET).chars().collect();
+        alphabet.sort_unstable();
+        alphabet.dedup();
+        if alphabet.len() < 2 {
+            bail!("Invalid pattern: alphabet needs at least 2 distinct characters");
+        }
+        if alphabet.iter().any(|c| !c.is_ascii_graphic()) {
+            bail!("Invalid pattern: alphabet must be printable ASCII without spaces");
+        }
+
+        let mut parts = Vec::new();
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                'X' => parts.push(Part::Random),
+                '\\' => match chars.next() {
+                    Some(next) => parts.push(Part::Literal(next)),
+                    None => bail!("Invalid pattern: trailing backslash"),
+                },
+                c if c.is_ascii_graphic() => parts.push(Part::Literal(c)),
+                _ => bail!("Invalid pattern: only printable ASCII without spaces is allowed"),
+            }
+        }
+        if !parts.iter().any(|p| matches!(p, Part::Random)) {
+            bail!("Invalid pattern: needs at least one X placeholder");
+        }
+        if parts.len() > MAX_CODE_LEN {
+            bail!("Invalid pattern: codes can be at most {MAX_CODE_LEN} characters");
+        }
+        Ok(CodePattern { parts, alphabet })
+    }
+
+    // Number of distinct codes the pattern can produce (saturating).
+    pub fn capacity(&self) -> f64 {
+        let slots = self.parts.iter().filter(|p| matches!(p, Part::Random)).count();
+        (self.alphabet.len() as f64).powi(slots as i32)
+    }
+
+    // Whether `count` codes leave enough of the space free for random draws.
+    pub fn has_room_for(&self, count: usize) -> bool {
+        self.capacity() >= count as f64 * HEADROOM
+    }
+
+    pub fn generate(&self) -> String {
+        self.parts
+            .iter()
+            .map(|p| match p {
+                Part::Literal(c) => *c,
+                Part::Random => self.alphabet[uniform_index(self.alphabet.len())],
+            })
+            .collect()
+    }
+}
+
+// Unbiased index in 0..n (rejection sampling instead of a plain modulo).
+fn uniform_index(n: usize) -> usize {
+    let n = n as u32;
+    let zone = u32::MAX - (u32::MAX % n);
+    loop {
+        let v = OsRng.next_u32();
+        if v < zone {
+            return (v % n) as usize;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn capacity_counts_only_placeholders() {
+        let p = CodePattern::parse("SUMMER-XXXX", None).unwrap();
+        assert_eq!(p.capacity(), 32f64.powi(4));
+        // `\X` is a literal, and duplicate alphabet characters count once.
+        let p = CodePattern::parse("\\XXX", Some("AABBC")).unwrap();
+        assert_eq!(p.capacity(), 9.0);
+    }
+
+    #[test]
+    fn room_for_needs_a_hundred_codes_per_code() {
+        let p = CodePattern::parse("XXX", None).unwrap(); // 32^3 = 32768
+        assert!(p.has_room_for(327));
+        assert!(!p.has_room_for(328));
+        let p = CodePattern::parse("SUMMER-XXXX-XXXX", None).unwrap();
+        assert!(p.has_room_for(10_000));
+    }
+
+    #[test]
+    fn generated_codes_follow_the_pattern() {
+        let p = CodePattern::parse("AB-\\X-XXXX", Some("xyz")).unwrap();
+        for _ in 0..100 {
+            let code = p.generate();
+            assert_eq!(code.len(), 9);
+            assert!(code.starts_with("AB-X-"));
+            assert!(code[5..].chars().all(|c| "xyz".contains(c)));
+        }
+    }
+
+    #[test]
+    fn draws_cover_a_small_space() {
+        // 4 possible codes in 1000 draws: every one shows up, nothing else does.
+        let p = CodePattern::parse("XX", Some("AB")).unwrap();
+        let seen: HashSet<String> = (0..1000).map(|_| p.generate()).collect();
+        let all: HashSet<String> = ["AA", "AB", "BA", "BB"].map(String::from).into();
+        assert_eq!(seen, all);
+    }
+
+    #[test]
+    fn rejects_bad_patterns() {
+        for (pattern, alphabet) in [
+            ("SUMMER", None),
+            ("XX\\", None),
+            ("XX X", None),
+            ("XXXX", Some("A")),
+            ("XXXX", Some("A B")),
+        ] {
+            assert!(CodePattern::parse(pattern, alphabet).is_err(), "{pattern:?} {alphabet:?}");
+        }
+        assert!(CodePattern::parse(&"X".repeat(MAX_CODE_LEN), None).is_ok());
+        assert!(CodePattern::parse(&"X".repeat(MAX_CODE_LEN + 1), None).is_err());
+    }
+}
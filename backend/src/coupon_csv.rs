@@ -0,0 +1,523 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::authz::{Permission, Principal};
+use crate::{auth, db, discount, schema, AppCtx};
+
+// ---------- Admin CSV import/export ----------
+
+const EXPORT_COLUMNS: [&str; 19] = [
+    "code", "description", "service", "valid_from", "expires_at", "owner_id", "created_at",
+    "discount_kind", "discount_value", "discount_currency", "discount_sku",
+    "min_order_amount", "max_discount_amount", "batch_id",
+    "max_redemptions", "max_redemptions_per_user", "redemption_count",
+    "max_claims", "claim_count",
+];
+
+// Same meaning as the `createCoupon` input fields; timestamps are RFC 3339.
+const IMPORT_COLUMNS: [&str; 16] = [
+    "code", "description", "service", "valid_from", "expires_at", "expires_in_days", "owner_id",
+    "discount_kind", "discount_value", "discount_currency", "discount_sku",
+    "min_order_amount", "max_discount_amount",
+    "max_redemptions", "max_redemptions_per_user", "max_claims",
+];
+// Written by export for reference; skipped on import so an export can be re-imported as is.
+const EXPORT_ONLY_COLUMNS: [&str; 4] = ["created_at", "batch_id", "redemption_count", "claim_count"];
+const REQUIRED_COLUMNS: [&str; 3] = ["code", "description", "service"];
+const DISCOUNT_COLUMNS: std::ops::Range<usize> = 8..13; // after discount_kind
+
+// Usage caps are written as a number or this word; empty on import means the default (1).
+const UNLIMITED: &str = "unlimited";
+
+const MAX_IMPORT_ROWS: usize = 10_000;
+
+type HttpError = (StatusCode, String);
+
+/// Query string for `GET /admin/coupons/export`; mirrors `listCoupons(activeOnly, filter, sort)`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportParams {
+    active_only: Option<bool>,
+    service: Option<String>,
+    /// Comma-separated
+    services: Option<String>,
+    code_prefix: Option<String>,
+    description_contains: Option<String>,
+    expires_after: Option<i64>,
+    expires_before: Option<i64>,
+    created_after: Option<i64>,
+    created_before: Option<i64>,
+    owned: Option<bool>,
+    owner_id: Option<String>,
+    batch_id: Option<String>,
+    /// created_at | expires_at | code | service
+    sort: Option<String>,
+    /// asc | desc
+    direction: Option<String>,
+}
+
+/// Query string for `POST /admin/coupons/import`; without `commit=true` nothing is saved.
+#[derive(Deserialize)]
+pub struct ImportParams {
+    #[serde(default)]
+    commit: bool,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    committed: bool,
+    /// Data rows in the file
+    rows: usize,
+    /// Rows that passed every check
+    valid: usize,
+    errors: Vec<RowError>,
+}
+
+#[derive(Serialize)]
+pub struct RowError {
+    /// Line in the CSV file (the header is line 1)
+    line: u64,
+    code: Option<String>,
+    message: String,
+}
+
+pub async fn export_coupons(
+    State(ctx): State<AppCtx>,
+    headers: HeaderMap,
+    Query(p): Query<ExportParams>,
+) -> Result<Response, HttpError> {
+    let staff = require_permission(&ctx, &headers, Permission::CouponRead).await?;
+
+    let sort = match p.sort.as_deref() {
+        None => None,
+        Some(s) => {
+            let field = match s.to_ascii_lowercase().as_str() {
+                "created_at" => schema::CouponSortField::CreatedAt,
+                "expires_at" => schema::CouponSortField::ExpiresAt,
+                "code" => schema::CouponSortField::Code,
+                "service" => schema::CouponSortField::Service,
+                _ => return Err((StatusCode::BAD_REQUEST, format!("Unknown sort field {s}"))),
+            };
+            let direction = match p.direction.as_deref().map(str::to_ascii_lowercase).as_deref() {
+                None | Some("desc") => schema::SortDirection::Desc,
+                Some("asc") => schema::SortDirection::Asc,
+                Some(d) => return Err((StatusCode::BAD_REQUEST, format!("Unknown sort direction {d}"))),
+            };
+            Some(schema::CouponSort { field, direction })
+        }
+    };
+    let filter = schema::CouponFilter {
+        service: p.service,
+        services: p.services.map(|s| s.split(',').map(|s| s.trim().to_string()).collect()),
+        code_prefix: p.code_prefix,
+        description_contains: p.description_contains,
+        expires_after: p.expires_after,
+        expires_before: p.expires_before,
+        created_after: p.created_after,
+        created_before: p.created_before,
+        owned: p.owned,
+        owner_id: p.owner_id,
+        batch_id: p.batch_id,
+    };
+    let mut q = schema::coupon_query(p.active_only.unwrap_or(true), filter, sort);
+    q.services = staff.scope_services(q.services).map_err(|e| (StatusCode::FORBIDDEN, e.to_string()))?;
+    let coupons = db::all_coupons(&ctx.state.pool, &q).await.map_err(internal)?;
+
+    let mut w = csv::Writer::from_writer(Vec::new());
+    w.write_record(EXPORT_COLUMNS).map_err(internal)?;
+    for c in coupons {
+        w.write_record(export_record(c)).map_err(internal)?;
+    }
+    let body = w.into_inner().map_err(internal)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"coupons.csv\""),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+// One CSV row, in EXPORT_COLUMNS order; timestamps are RFC 3339 like on import.
+fn export_record(c: db::DbCoupon) -> [String; 19] {
+    let ts = |t: i64| {
+        DateTime::from_timestamp(t, 0)
+            .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true))
+            .unwrap_or_default()
+    };
+    let dec = |v: Option<Decimal>| v.map(|v| v.to_string()).unwrap_or_default();
+    let cap = |v: Option<i64>| v.map_or(UNLIMITED.to_string(), |v| v.to_string());
+    let d = c.discount.as_ref();
+    [
+        c.code,
+        c.description,
+        c.service,
+        ts(c.valid_from),
+        ts(c.expires_at),
+        c.owner_id.unwrap_or_default(),
+        ts(c.created_at),
+        d.map(|d| d.kind.as_str().to_string()).unwrap_or_default(),
+        dec(d.and_then(|d| d.value)),
+        d.and_then(|d| d.currency.clone()).unwrap_or_default(),
+        d.and_then(|d| d.sku.clone()).unwrap_or_default(),
+        dec(d.and_then(|d| d.min_order_amount)),
+        dec(d.and_then(|d| d.max_discount_amount)),
+        c.batch_id.unwrap_or_default(),
+        cap(c.limits.max_redemptions),
+        cap(c.limits.max_per_user),
+        c.redemption_count.to_string(),
+        cap(c.limits.max_claims),
+        c.claim_count.to_string(),
+    ]
+}
+
+// Validates every row, then inserts them all in one transaction. Any error rolls the
+// whole file back; so does a dry run, after database checks (taken codes, unknown owners) have run.
+pub async fn import_coupons(
+    State(ctx): State<AppCtx>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(p): Query<ImportParams>,
+    body: String,
+) -> Result<Response, HttpError> {
+    let staff = require_permission(&ctx, &headers, Permission::CouponCreate).await?;
+    let actor = db::Actor {
+        user_id: staff.user_id().map(str::to_string),
+        api_key_id: staff.api_key_id().map(str::to_string),
+        ip: Some(addr.ip().to_string()),
+    };
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(body.as_bytes());
+    let cols = rdr
+        .headers()
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid CSV: {e}")))
+        .and_then(|h| ImportColumns::from_headers(h).map_err(|e| (StatusCode::BAD_REQUEST, e)))?;
+
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    let mut seen = HashSet::new();
+    let mut total = 0;
+    for rec in rdr.records() {
+        total += 1;
+        if total > MAX_IMPORT_ROWS {
+            return Err((StatusCode::BAD_REQUEST, format!("At most {MAX_IMPORT_ROWS} rows per import")));
+        }
+        let rec = match rec {
+            Ok(rec) => rec,
+            Err(e) => {
+                let line = e.position().map_or(0, |p| p.line());
+                errors.push(RowError { line, code: None, message: e.to_string() });
+                continue;
+            }
+        };
+        let line = rec.position().map_or(0, |p| p.line());
+        match cols.parse(&rec) {
+            Ok(row) if !seen.insert(row.code.clone()) => errors.push(RowError {
+                line,
+                code: Some(row.code),
+                message: "duplicate code in this file".into(),
+            }),
+            Ok(row) if staff.check_service(&row.service).is_err() => errors.push(RowError {
+                line,
+                code: Some(row.code),
+                message: format!("service {:?} is outside this API key's services", row.service),
+            }),
+            Ok(row) => rows.push((line, row)),
+            Err(message) => errors.push(RowError {
+                line,
+                code: cols.get(&rec, "code").map(str::to_string),
+                message,
+            }),
+        }
+    }
+
+    let mut tx = ctx.state.pool.begin().await.map_err(internal)?;
+    let mut created = Vec::new();
+    for (line, row) in &rows {
+        let res = db::insert_coupon(
+            &mut tx,
+            &db::NewCoupon {
+                code: &row.code,
+                description: &row.description,
+                service: &row.service,
+                valid_from: row.valid_from,
+                expires_at: row.expires_at,
+                owner_id: row.owner_id.as_deref(),
+                discount: row.discount.as_ref(),
+                limits: row.limits,
+            },
+            &actor,
+        ).await;
+        match res {
+            Ok(c) => created.push((c, row.owner_id.clone())),
+            Err(e) => {
+                let message = db::coupon_constraint_error(&e).ok_or_else(|| internal(&e))?;
+                errors.push(RowError { line: *line, code: Some(row.code.clone()), message: message.to_string() });
+            }
+        }
+    }
+    errors.sort_by_key(|e| e.line);
+
+    let committed = p.commit && errors.is_empty();
+    if committed {
+        tx.commit().await.map_err(internal)?;
+        for (c, owner) in created.iter().cloned() {
+            ctx.state.publish(schema::CouponEventKind::Created, c, owner.into_iter().collect());
+        }
+    } else {
+        tx.rollback().await.map_err(internal)?;
+    }
+
+    let status = if errors.is_empty() { StatusCode::OK } else { StatusCode::UNPROCESSABLE_ENTITY };
+    let report = ImportReport { committed, rows: total, valid: created.len(), errors };
+    Ok((status, Json(report)).into_response())
+}
+
+struct ImportRow {
+    code: String,
+    description: String,
+    service: String,
+    valid_from: i64,
+    expires_at: i64,
+    owner_id: Option<String>,
+    discount: Option<discount::Discount>,
+    limits: db::UsageLimits,
+}
+
+// Header name -> column index.
+struct ImportColumns(HashMap<&'static str, usize>);
+
+impl ImportColumns {
+    fn from_headers(h: &csv::StringRecord) -> Result<Self, String> {
+        let mut idx = HashMap::new();
+        for (i, name) in h.iter().enumerate() {
+            let name = name.to_ascii_lowercase();
+            if EXPORT_ONLY_COLUMNS.contains(&name.as_str()) {
+                continue;
+            }
+            let Some(col) = IMPORT_COLUMNS.iter().find(|c| **c == name) else {
+                return Err(format!("Unknown column {name:?}; expected {}", IMPORT_COLUMNS.join(",")));
+            };
+            if idx.insert(*col, i).is_some() {
+                return Err(format!("Column {name:?} appears twice"));
+            }
+        }
+        if let Some(missing) = REQUIRED_COLUMNS.iter().find(|c| !idx.contains_key(*c)) {
+            return Err(format!("Missing required column {missing:?}"));
+        }
+        if !idx.contains_key("expires_at") && !idx.contains_key("expires_in_days") {
+            return Err("Missing column \"expires_at\" or \"expires_in_days\"".into());
+        }
+        Ok(ImportColumns(idx))
+    }
+
+    // Non-empty value of `col`, if the file has that column.
+    fn get<'r>(&self, rec: &'r csv::StringRecord, col: &str) -> Option<&'r str> {
+        self.0.get(col).and_then(|&i| rec.get(i)).filter(|v| !v.is_empty())
+    }
+
+    fn parse(&self, rec: &csv::StringRecord) -> Result<ImportRow, String> {
+        let code = self.get(rec, "code").ok_or("code is required")?;
+        let now = Utc::now();
+        let valid_from = self.timestamp(rec, "valid_from")?.unwrap_or(now.timestamp());
+        let expires_at = match (self.timestamp(rec, "expires_at")?, self.get(rec, "expires_in_days")) {
+            (Some(_), Some(_)) => return Err("give expires_at or expires_in_days, not both".into()),
+            (Some(t), None) => t,
+            (None, Some(days)) => days
+                .parse()
+                .ok()
+                .and_then(Duration::try_days)
+                .map(|d| (now + d).timestamp())
+                .ok_or("expires_in_days must be a whole number")?,
+            (None, None) => return Err("expires_at or expires_in_days is required".into()),
+        };
+        if expires_at <= valid_from {
+            return Err("expires_at must be after valid_from".into());
+        }
+        Ok(ImportRow {
+            code: code.to_string(),
+            description: self.get(rec, "description").unwrap_or_default().to_string(),
+            service: self.get(rec, "service").unwrap_or_default().to_string(),
+            valid_from,
+            expires_at,
+            owner_id: self.get(rec, "owner_id").map(str::to_string),
+            discount: self.discount(rec)?,
+            limits: db::UsageLimits {
+                max_redemptions: self.usage_cap(rec, "max_redemptions")?,
+                max_per_user: self.usage_cap(rec, "max_redemptions_per_user")?,
+                max_claims: self.usage_cap(rec, "max_claims")?,
+            },
+        })
+    }
+
+    fn timestamp(&self, rec: &csv::StringRecord, col: &str) -> Result<Option<i64>, String> {
+        self.get(rec, col)
+            .map(|v| {
+                DateTime::parse_from_rfc3339(v)
+                    .map(|t| t.timestamp())
+                    .map_err(|_| format!("{col} must be an RFC 3339 timestamp like 2025-11-03T00:00:00Z"))
+            })
+            .transpose()
+    }
+
+    fn usage_cap(&self, rec: &csv::StringRecord, col: &str) -> Result<Option<i64>, String> {
+        match self.get(rec, col) {
+            None => Ok(Some(1)),
+            Some(v) if v.eq_ignore_ascii_case(UNLIMITED) => Ok(None),
+            Some(v) => match v.parse::<i64>() {
+                Ok(n) if n >= 1 => Ok(Some(n)),
+                _ => Err(format!("{col} must be a whole number of at least 1, or {UNLIMITED:?}")),
+            },
+        }
+    }
+
+    fn discount(&self, rec: &csv::StringRecord) -> Result<Option<discount::Discount>, String> {
+        let Some(kind) = self.get(rec, "discount_kind") else {
+            if IMPORT_COLUMNS[DISCOUNT_COLUMNS].iter().any(|c| self.get(rec, c).is_some()) {
+                return Err("discount columns are set but discount_kind is empty".into());
+            }
+            return Ok(None);
+        };
+        let kind = discount::DiscountKind::parse(&kind.to_ascii_lowercase()).ok_or_else(|| {
+            format!("unknown discount_kind {kind:?}; expected percentage, fixed_amount, free_shipping or free_item")
+        })?;
+        let dec = |col: &str| {
+            self.get(rec, col)
+                .map(|v| v.parse::<Decimal>().map_err(|_| format!("{col} must be a number")))
+                .transpose()
+        };
+        discount::Discount {
+            kind,
+            value: dec("discount_value")?,
+            currency: self.get(rec, "discount_currency").map(str::to_string),
+            sku: self.get(rec, "discount_sku").map(str::to_string),
+            min_order_amount: dec("min_order_amount")?,
+            max_discount_amount: dec("max_discount_amount")?,
+        }
+        .validate()
+        .map(Some)
+        .map_err(|e| e.to_string())
+    }
+}
+
+// A bearer token for a user with `permission`, or failing that an API key (X-Api-Key) holding it.
+async fn require_permission(ctx: &AppCtx, headers: &HeaderMap, permission: Permission) -> Result<Principal, HttpError> {
+    let api_key = headers.get(auth::API_KEY_HEADER).and_then(|v| v.to_str().ok());
+    if let (None, Some(key)) = (headers.get(header::AUTHORIZATION), api_key) {
+        let k = auth::parse_api_key(&ctx.state.pool, key.trim())
+            .await
+            .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid or revoked API key".to_string()))?;
+        if !k.permissions.iter().any(|p| p == permission.as_str()) {
+            return Err((StatusCode::FORBIDDEN, format!("API key lacks the {} permission", permission.as_str())));
+        }
+        return Ok(Principal::ApiKey { id: k.id, services: k.services });
+    }
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|s| s.strip_prefix("Bearer "))
+        .ok_or((StatusCode::UNAUTHORIZED, "Missing bearer token".to_string()))?;
+    let user_id = auth::parse_jwt(&ctx.state.pool, &ctx.state.jwt_secret, token)
+        .await
+        .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid or expired token".to_string()))?;
+    if !db::user_has_permission(&ctx.state.pool, &user_id, permission).await.map_err(internal)? {
+        return Err((StatusCode::FORBIDDEN, format!("Requires the {} permission", permission.as_str())));
+    }
+    Ok(Principal::User(user_id))
+}
+
+fn internal(e: impl std::fmt::Display) -> HttpError {
+    tracing::error!("coupon CSV request failed: {e}");
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal error".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(fields: &[&str]) -> csv::StringRecord {
+        csv::StringRecord::from(fields.to_vec())
+    }
+
+    #[test]
+    fn export_header_is_accepted_on_import() {
+        let cols = ImportColumns::from_headers(&record(&EXPORT_COLUMNS)).unwrap();
+        assert!(EXPORT_ONLY_COLUMNS.iter().all(|c| !cols.0.contains_key(c)));
+        assert!(IMPORT_COLUMNS.iter().filter(|c| **c != "expires_in_days").all(|c| cols.0.contains_key(c)));
+    }
+
+    #[test]
+    fn export_then_import_round_trips() {
+        let coupon = db::DbCoupon {
+            id: "c1".into(),
+            code: "SUMMER-AB12".into(),
+            description: "Summer, \"sale\"".into(),
+            service: "my-store".into(),
+            valid_from: 1_760_000_000,
+            expires_at: 1_770_000_000,
+            owner_id: Some("u1".into()),
+            created_at: 1_750_000_000,
+            discount: Some(discount::Discount {
+                kind: discount::DiscountKind::Percentage,
+                value: Some("12.5".parse().unwrap()),
+                currency: Some("EUR".into()),
+                sku: None,
+                min_order_amount: Some("20".parse().unwrap()),
+                max_discount_amount: Some("7.50".parse().unwrap()),
+            }),
+            batch_id: Some("b1".into()),
+            limits: db::UsageLimits { max_redemptions: Some(100), max_per_user: Some(1), max_claims: None },
+            redemption_count: 3,
+            claim_count: 4,
+            deleted_at: None,
+            deleted_by: None,
+        };
+
+        let mut w = csv::Writer::from_writer(Vec::new());
+        w.write_record(EXPORT_COLUMNS).unwrap();
+        w.write_record(export_record(coupon.clone())).unwrap();
+        let body = w.into_inner().unwrap();
+
+        let mut rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(body.as_slice());
+        let cols = ImportColumns::from_headers(rdr.headers().unwrap()).unwrap();
+        let rows: Vec<_> = rdr.records().map(|r| cols.parse(&r.unwrap()).unwrap()).collect();
+        let [row] = rows.as_slice() else { panic!("expected one row") };
+
+        assert_eq!(row.code, coupon.code);
+        assert_eq!(row.description, coupon.description);
+        assert_eq!(row.service, coupon.service);
+        assert_eq!(row.valid_from, coupon.valid_from);
+        assert_eq!(row.expires_at, coupon.expires_at);
+        assert_eq!(row.owner_id, coupon.owner_id);
+        assert_eq!(row.limits.max_redemptions, Some(100));
+        assert_eq!(row.limits.max_per_user, Some(1));
+        assert_eq!(row.limits.max_claims, None);
+        let (got, want) = (row.discount.as_ref().unwrap(), coupon.discount.as_ref().unwrap());
+        assert_eq!(got.kind, want.kind);
+        assert_eq!(got.value, want.value);
+        assert_eq!(got.currency, want.currency);
+        assert_eq!(got.min_order_amount, want.min_order_amount);
+        assert_eq!(got.max_discount_amount, want.max_discount_amount);
+    }
+
+    #[test]
+    fn unknown_and_repeated_columns_are_rejected() {
+        assert!(ImportColumns::from_headers(&record(&["code", "description", "service", "expires_at", "colour"])).is_err());
+        assert!(ImportColumns::from_headers(&record(&["code", "description", "service", "expires_at", "code"])).is_err());
+        assert!(ImportColumns::from_headers(&record(&["code", "description", "service"])).is_err());
+    }
+}
@@ -1,8 +1,14 @@
 use anyhow::Result;
-use chrono::{Utc, Duration};
-use sqlx::{SqlitePool, Row};
+use chrono::Utc;
+use serde_json::{json, Value};
+use sqlx::{QueryBuilder, Row, Sqlite, SqliteConnection, SqlitePool, sqlite::SqliteRow};
 use uuid::Uuid;
 
+use crate::auth::{canonical_email, LoginThrottle, ACCOUNT_THROTTLE, LOGIN_FAILURE_WINDOW_SECS, MFA_MAX_ATTEMPTS};
+use crate::authz::{Permission, SUPERADMIN};
+use crate::discount::{Discount, DiscountKind};
+use crate::error::ApiError;
+
 // ---------- Users ----------
 
 #[derive(Clone)]
@@ -10,67 +16,444 @@ pub struct DbUser {
     pub id: String,
     pub email: String,
     pub password_hash: String,
-    pub is_admin: bool,
     pub created_at: i64,
+    pub suspended_at: Option<i64>,
+    pub suspended_reason: Option<String>,
+    pub email_verified_at: Option<i64>,
+    pub totp_enabled_at: Option<i64>,
 }
 
 pub async fn pool(dsn: &str) -> Result<SqlitePool> {
     Ok(SqlitePool::connect(dsn).await?)
 }
 
-pub async fn find_user_by_email(pool: &SqlitePool, email: &str) -> Result<Option<DbUser>> {
-    let row = sqlx::query(
-        "SELECT id,email,password_hash,is_admin,created_at FROM users WHERE email = ?",
-    )
-    .bind(email)
-    .fetch_optional(pool)
-    .await?;
+const USER_COLS: &str =
+    "id,email,password_hash,created_at,suspended_at,suspended_reason,email_verified_at,totp_enabled_at";
 
-    Ok(row.map(|r| DbUser {
+fn user_from_row(r: &SqliteRow) -> DbUser {
+    DbUser {
         id: r.get("id"),
         email: r.get("email"),
         password_hash: r.get("password_hash"),
-        is_admin: r.get::<i64,_>("is_admin") == 1,
         created_at: r.get("created_at"),
-    }))
+        suspended_at: r.get("suspended_at"),
+        suspended_reason: r.get("suspended_reason"),
+        email_verified_at: r.get("email_verified_at"),
+        totp_enabled_at: r.get("totp_enabled_at"),
+    }
+}
+
+pub async fn find_user_by_email(pool: &SqlitePool, email: &str) -> Result<Option<DbUser>> {
+    let row = sqlx::query(&format!("SELECT {USER_COLS} FROM users WHERE email = ?"))
+        .bind(email)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.as_ref().map(user_from_row))
+}
+
+async fn user_by_id(conn: &mut SqliteConnection, id: &str) -> Result<Option<DbUser>> {
+    let row = sqlx::query(&format!("SELECT {USER_COLS} FROM users WHERE id = ?"))
+        .bind(id)
+        .fetch_optional(conn)
+        .await?;
+
+    Ok(row.as_ref().map(user_from_row))
+}
+
+pub async fn find_user_by_id(pool: &SqlitePool, id: &str) -> Result<Option<DbUser>> {
+    user_by_id(&mut *pool.acquire().await?, id).await
+}
+
+// Exists and isn't suspended; checked on every authenticated request.
+// False if the account is gone or suspended, or the token (issued at `issued_at_ms`) isn't
+// strictly newer than the last password reset or suspension, so one issued in the same
+// instant doesn't survive it.
+pub async fn is_session_active(pool: &SqlitePool, id: &str, issued_at_ms: i64) -> Result<bool> {
+    let one: Option<i64> = sqlx::query_scalar(
+        "SELECT 1 FROM users WHERE id=? AND suspended_at IS NULL
+           AND (sessions_valid_after IS NULL OR sessions_valid_after < ?)",
+    )
+    .bind(id)
+    .bind(issued_at_ms)
+    .fetch_optional(pool)
+    .await?;
+    Ok(one.is_some())
+}
+
+pub async fn is_email_verified(pool: &SqlitePool, id: &str) -> Result<bool> {
+    let one: Option<i64> = sqlx::query_scalar("SELECT 1 FROM users WHERE id=? AND email_verified_at IS NOT NULL")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(one.is_some())
 }
 
-pub async fn first_user_exists(pool: &SqlitePool) -> Result<bool> {
-    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users").fetch_one(pool).await?;
-    Ok(count > 0)
+pub async fn superadmin_exists(pool: &SqlitePool) -> Result<bool> {
+    let one: Option<i64> = sqlx::query_scalar("SELECT 1 FROM user_roles WHERE role=? LIMIT 1")
+        .bind(SUPERADMIN)
+        .fetch_optional(pool)
+        .await?;
+    Ok(one.is_some())
+}
+
+// Makes the user superadmin if nobody is yet, in one statement so two callers can't both win.
+// False if there already is one.
+pub async fn bootstrap_superadmin(pool: &SqlitePool, user_id: &str, actor: &Actor) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let granted = grant_first_superadmin(&mut tx, user_id, Utc::now().timestamp()).await?;
+    if !granted {
+        return Ok(false);
+    }
+    let after = roles_of(&mut tx, user_id).await?;
+    record_audit(&mut tx, actor, "user.bootstrap_admin", ("user", user_id), None, Some(json!({ "roles": after }))).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+async fn grant_first_superadmin(conn: &mut SqliteConnection, user_id: &str, now: i64) -> Result<bool> {
+    let n = sqlx::query(
+        "INSERT INTO user_roles(user_id,role,granted_at)
+         SELECT id, ?, ? FROM users WHERE id=? AND NOT EXISTS (SELECT 1 FROM user_roles WHERE role=?)",
+    )
+    .bind(SUPERADMIN)
+    .bind(now)
+    .bind(user_id)
+    .bind(SUPERADMIN)
+    .execute(conn)
+    .await?
+    .rows_affected();
+    Ok(n > 0)
 }
 
-pub async fn create_user(pool: &SqlitePool, email: &str, password_hash: &str, is_admin: bool) -> Result<DbUser> {
+// `bootstrap_admin`: grant superadmin if nobody has it yet (opt-in dev mode).
+// None if the email is taken.
+pub async fn create_user(
+    pool: &SqlitePool,
+    email: &str,
+    password_hash: &str,
+    bootstrap_admin: bool,
+    actor: &Actor,
+) -> Result<Option<DbUser>> {
     let id = Uuid::new_v4().to_string();
     let created_at = Utc::now().timestamp();
-    let is_admin_i = if is_admin { 1 } else { 0 };
 
-    sqlx::query("INSERT INTO users(id,email,password_hash,is_admin,created_at) VALUES(?,?,?,?,?)")
-        .bind(&id)
-        .bind(email)
-        .bind(password_hash)
-        .bind(is_admin_i)
-        .bind(created_at)
-        .execute(pool)
-        .await?;
+    let mut tx = pool.begin().await?;
+    let inserted = sqlx::query(
+        "INSERT INTO users(id,email,password_hash,created_at) VALUES(?,?,?,?) ON CONFLICT(email) DO NOTHING",
+    )
+    .bind(&id)
+    .bind(email)
+    .bind(password_hash)
+    .bind(created_at)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if inserted == 0 {
+        return Ok(None);
+    }
+    let admin = bootstrap_admin && grant_first_superadmin(&mut tx, &id, created_at).await?;
+    let after = json!({ "id": id, "email": email, "roles": Vec::from_iter(admin.then_some(SUPERADMIN)) });
+    record_audit(&mut tx, actor, "user.register", ("user", &id), None, Some(after)).await?;
+    tx.commit().await?;
 
-    Ok(DbUser {
+    Ok(Some(DbUser {
         id,
         email: email.to_string(),
         password_hash: password_hash.to_string(),
-        is_admin,
         created_at,
-    })
+        suspended_at: None,
+        suspended_reason: None,
+        email_verified_at: None,
+        totp_enabled_at: None,
+    }))
+}
+
+// Which users a listing covers; always ordered by email.
+#[derive(Default)]
+pub struct UserQuery {
+    pub email_contains: Option<String>, // case-insensitive substring
+    pub role: Option<String>,
+    pub suspended: Option<bool>,
+}
+
+fn push_user_filters(qb: &mut QueryBuilder<'_, Sqlite>, q: &UserQuery) {
+    if let Some(needle) = &q.email_contains {
+        qb.push(" AND email LIKE ").push_bind(format!("%{}%", like_escape(needle))).push(" ESCAPE '\\'");
+    }
+    if let Some(role) = &q.role {
+        qb.push(" AND EXISTS (SELECT 1 FROM user_roles ur WHERE ur.user_id = users.id AND ur.role = ")
+            .push_bind(role.clone())
+            .push(")");
+    }
+    match q.suspended {
+        Some(true) => { qb.push(" AND suspended_at IS NOT NULL"); }
+        Some(false) => { qb.push(" AND suspended_at IS NULL"); }
+        None => {}
+    }
+}
+
+// One page of users by email, strictly between the cursors (emails).
+// Same paging contract as list_coupons.
+pub async fn list_users(
+    pool: &SqlitePool,
+    q: &UserQuery,
+    after: Option<&str>,
+    before: Option<&str>,
+    limit: i64,
+    from_end: bool,
+) -> Result<(Vec<DbUser>, bool)> {
+    let mut qb = QueryBuilder::<Sqlite>::new(format!("SELECT {USER_COLS} FROM users WHERE 1=1"));
+    push_user_filters(&mut qb, q);
+    if let Some(email) = after {
+        qb.push(" AND email > ").push_bind(email.to_string());
+    }
+    if let Some(email) = before {
+        qb.push(" AND email < ").push_bind(email.to_string());
+    }
+    qb.push(if from_end { " ORDER BY email DESC" } else { " ORDER BY email ASC" });
+    qb.push(" LIMIT ").push_bind(limit + 1);
+
+    let rows = qb.build().fetch_all(pool).await?;
+    let has_more = rows.len() as i64 > limit;
+    let mut page: Vec<DbUser> = rows.iter().take(limit as usize).map(user_from_row).collect();
+    if from_end {
+        page.reverse();
+    }
+    Ok((page, has_more))
+}
+
+pub async fn count_users(pool: &SqlitePool, q: &UserQuery) -> Result<i64> {
+    let mut qb = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM users WHERE 1=1");
+    push_user_filters(&mut qb, q);
+    Ok(qb.build_query_scalar().fetch_one(pool).await?)
+}
+
+async fn user_snapshot(conn: &mut SqliteConnection, u: &DbUser) -> Result<Value> {
+    let roles = roles_of(conn, &u.id).await?;
+    Ok(json!({
+        "id": u.id,
+        "email": u.email,
+        "roles": roles,
+        "created_at": u.created_at,
+        "suspended_at": u.suspended_at,
+        "suspended_reason": u.suspended_reason,
+        "email_verified_at": u.email_verified_at,
+    }))
 }
 
-pub async fn is_user_admin(pool: &SqlitePool, user_id: &str) -> Result<bool> {
-    let one: Option<i64> = sqlx::query_scalar("SELECT 1 FROM users WHERE id=? AND is_admin=1")
+// Refuses to take away the last working superadmin (suspending, deleting or demoting them).
+async fn ensure_other_superadmin(conn: &mut SqliteConnection, user_id: &str) -> Result<()> {
+    let is_superadmin: Option<i64> = sqlx::query_scalar("SELECT 1 FROM user_roles WHERE user_id=? AND role=?")
         .bind(user_id)
-        .fetch_optional(pool)
+        .bind(SUPERADMIN)
+        .fetch_optional(&mut *conn)
+        .await?;
+    if is_superadmin.is_none() {
+        return Ok(());
+    }
+    let others: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM user_roles ur JOIN users u ON u.id = ur.user_id
+         WHERE ur.role=? AND ur.user_id <> ? AND u.suspended_at IS NULL"
+    )
+    .bind(SUPERADMIN)
+    .bind(user_id)
+    .fetch_one(&mut *conn)
+    .await?;
+    if others == 0 {
+        anyhow::bail!(ApiError::conflict(format!("Can't remove the last active {SUPERADMIN}")));
+    }
+    Ok(())
+}
+
+// Blocks login and every existing token. False if unknown or already suspended.
+pub async fn suspend_user(pool: &SqlitePool, user_id: &str, reason: Option<&str>, actor: &Actor) -> Result<bool> {
+    let now = Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+    let Some(cur) = user_by_id(&mut tx, user_id).await? else { return Ok(false); };
+    if cur.suspended_at.is_some() {
+        return Ok(false);
+    }
+    ensure_other_superadmin(&mut tx, user_id).await?;
+    let before = user_snapshot(&mut tx, &cur).await?;
+
+    // Ends every session for good: access tokens from before now stay refused after
+    // an unsuspend (auth::parse_jwt), and refresh tokens are retired outright.
+    sqlx::query("UPDATE users SET suspended_at=?, suspended_reason=?, sessions_valid_after=? WHERE id=?")
+        .bind(now)
+        .bind(reason)
+        .bind(Utc::now().timestamp_millis())
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE refresh_tokens SET revoked_at=? WHERE user_id=? AND revoked_at IS NULL")
+        .bind(now)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let after = DbUser { suspended_at: Some(now), suspended_reason: reason.map(str::to_string), ..cur };
+    let after = user_snapshot(&mut tx, &after).await?;
+    record_audit(&mut tx, actor, "user.suspend", ("user", user_id), Some(before), Some(after)).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+// False if unknown or not suspended. The user has to log in again.
+pub async fn unsuspend_user(pool: &SqlitePool, user_id: &str, actor: &Actor) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let Some(cur) = user_by_id(&mut tx, user_id).await? else { return Ok(false); };
+    if cur.suspended_at.is_none() {
+        return Ok(false);
+    }
+    let before = user_snapshot(&mut tx, &cur).await?;
+    sqlx::query("UPDATE users SET suspended_at=NULL, suspended_reason=NULL WHERE id=?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    let after = user_snapshot(&mut tx, &DbUser { suspended_at: None, suspended_reason: None, ..cur }).await?;
+    record_audit(&mut tx, actor, "user.unsuspend", ("user", user_id), Some(before), Some(after)).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+// Removes the account with its roles, claims, redemptions and sessions.
+pub async fn delete_user(pool: &SqlitePool, user_id: &str, actor: &Actor) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let Some(cur) = user_by_id(&mut tx, user_id).await? else { return Ok(false); };
+    ensure_other_superadmin(&mut tx, user_id).await?;
+    let before = user_snapshot(&mut tx, &cur).await?;
+    sqlx::query("DELETE FROM users WHERE id=?")
+        .bind(user_id)
+        .execute(&mut *tx)
         .await?;
+    record_audit(&mut tx, actor, "user.delete", ("user", user_id), Some(before), None).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+// ---------- Roles ----------
+
+pub struct DbRole {
+    pub name: String,
+    pub description: String,
+    pub permissions: Vec<String>,
+}
+
+pub async fn user_has_permission(pool: &SqlitePool, user_id: &str, permission: Permission) -> Result<bool> {
+    let one: Option<i64> = sqlx::query_scalar(
+        "SELECT 1 FROM user_roles ur JOIN role_permissions rp ON rp.role = ur.role
+         WHERE ur.user_id=? AND rp.permission=? LIMIT 1"
+    )
+    .bind(user_id)
+    .bind(permission.as_str())
+    .fetch_optional(pool)
+    .await?;
     Ok(one.is_some())
 }
 
+async fn roles_of(conn: &mut SqliteConnection, user_id: &str) -> Result<Vec<String>> {
+    Ok(sqlx::query_scalar("SELECT role FROM user_roles WHERE user_id=? ORDER BY role")
+        .bind(user_id)
+        .fetch_all(conn)
+        .await?)
+}
+
+pub async fn user_roles(pool: &SqlitePool, user_id: &str) -> Result<Vec<String>> {
+    roles_of(&mut *pool.acquire().await?, user_id).await
+}
+
+pub async fn user_permissions(pool: &SqlitePool, user_id: &str) -> Result<Vec<String>> {
+    Ok(sqlx::query_scalar(
+        "SELECT DISTINCT rp.permission FROM user_roles ur JOIN role_permissions rp ON rp.role = ur.role
+         WHERE ur.user_id=? ORDER BY rp.permission"
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?)
+}
+
+pub async fn list_roles(pool: &SqlitePool) -> Result<Vec<DbRole>> {
+    let rows = sqlx::query(
+        "SELECT r.name, r.description, rp.permission FROM roles r
+         LEFT JOIN role_permissions rp ON rp.role = r.name
+         ORDER BY r.name, rp.permission"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut roles: Vec<DbRole> = Vec::new();
+    for r in &rows {
+        let name: String = r.get("name");
+        if roles.last().is_none_or(|last| last.name != name) {
+            roles.push(DbRole { name, description: r.get("description"), permissions: Vec::new() });
+        }
+        if let (Some(role), Some(p)) = (roles.last_mut(), r.get::<Option<String>,_>("permission")) {
+            role.permissions.push(p);
+        }
+    }
+    Ok(roles)
+}
+
+// False if the user already had the role.
+pub async fn assign_role(pool: &SqlitePool, user_id: &str, role: &str, actor: &Actor) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let known: Option<i64> = sqlx::query_scalar("SELECT 1 FROM roles WHERE name=?")
+        .bind(role)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if known.is_none() {
+        anyhow::bail!(ApiError::not_found(format!("Unknown role {role}")));
+    }
+    let user: Option<i64> = sqlx::query_scalar("SELECT 1 FROM users WHERE id=?")
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if user.is_none() {
+        anyhow::bail!(ApiError::not_found(format!("Unknown user {user_id}")));
+    }
+
+    let before = roles_of(&mut tx, user_id).await?;
+    let n = sqlx::query("INSERT INTO user_roles(user_id,role,granted_at) VALUES(?,?,?)
+                         ON CONFLICT(user_id,role) DO NOTHING")
+        .bind(user_id)
+        .bind(role)
+        .bind(Utc::now().timestamp())
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if n == 0 {
+        return Ok(false);
+    }
+    let after = roles_of(&mut tx, user_id).await?;
+    record_audit(&mut tx, actor, "user.role_assign", ("user", user_id),
+                 Some(json!({ "roles": before })), Some(json!({ "roles": after }))).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+// False if the user didn't have the role. The last active superadmin can't be removed.
+pub async fn revoke_role(pool: &SqlitePool, user_id: &str, role: &str, actor: &Actor) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let before = roles_of(&mut tx, user_id).await?;
+    if !before.iter().any(|r| r == role) {
+        return Ok(false);
+    }
+    if role == SUPERADMIN {
+        ensure_other_superadmin(&mut tx, user_id).await?;
+    }
+    sqlx::query("DELETE FROM user_roles WHERE user_id=? AND role=?")
+        .bind(user_id)
+        .bind(role)
+        .execute(&mut *tx)
+        .await?;
+    let after = roles_of(&mut tx, user_id).await?;
+    record_audit(&mut tx, actor, "user.role_revoke", ("user", user_id),
+                 Some(json!({ "roles": before })), Some(json!({ "roles": after }))).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
 // ---------- Coupons ----------
 
 #[derive(Clone)]
@@ -79,165 +462,1859 @@ pub struct DbCoupon {
     pub code: String,
     pub description: String,
     pub service: String,
-    pub expires_at: i64,          // unix secs
-    pub owner_id: Option<String>, // nullable
+    pub valid_from: i64,          // unix secs; usable from here ...
+    pub expires_at: i64,          // ... until here
+    pub owner_id: Option<String>, // sole holder of a single-holder (max_claims = 1) coupon
     pub created_at: i64,
+    pub discount: Option<Discount>, // None = legacy, description only
+    pub batch_id: Option<String>,   // set for bulk-generated coupons
+    pub limits: UsageLimits,
+    pub redemption_count: i64,
+    pub claim_count: i64,           // current holders
+    pub deleted_at: Option<i64>,    // set while archived
+    pub deleted_by: Option<String>,
 }
 
-pub async fn create_coupon(
-    pool: &SqlitePool,
-    code: &str,
-    description: &str,
-    service: &str,
-    expires_in_days: i64,
-    owner_id: Option<&str>,
-) -> Result<DbCoupon> {
+impl DbCoupon {
+    // None = unlimited.
+    pub fn remaining_uses(&self) -> Option<i64> {
+        self.limits.max_redemptions.map(|m| (m - self.redemption_count).max(0))
+    }
+}
+
+// Claim and redemption caps; None = unlimited.
+#[derive(Clone, Copy, Debug)]
+pub struct UsageLimits {
+    pub max_redemptions: Option<i64>,
+    pub max_per_user: Option<i64>,
+    pub max_claims: Option<i64>, // users holding the coupon at once
+}
+
+impl Default for UsageLimits {
+    // One holder, single use: how every coupon worked before limits existed.
+    fn default() -> Self {
+        UsageLimits { max_redemptions: Some(1), max_per_user: Some(1), max_claims: Some(1) }
+    }
+}
+
+// Per-cap changes: Some(Some(n)) set, Some(None) unlimited, None leave unchanged.
+#[derive(Clone, Copy, Default)]
+pub struct UsageLimitsPatch {
+    pub max_redemptions: Option<Option<i64>>,
+    pub max_per_user: Option<Option<i64>>,
+    pub max_claims: Option<Option<i64>>,
+}
+
+impl UsageLimitsPatch {
+    fn apply(self, cur: UsageLimits) -> UsageLimits {
+        UsageLimits {
+            max_redemptions: self.max_redemptions.unwrap_or(cur.max_redemptions),
+            max_per_user: self.max_per_user.unwrap_or(cur.max_per_user),
+            max_claims: self.max_claims.unwrap_or(cur.max_claims),
+        }
+    }
+}
+
+// Column values for a discount, in the order of the discount_* columns.
+struct DiscountCols {
+    kind: Option<&'static str>,
+    value: Option<String>,
+    currency: Option<String>,
+    sku: Option<String>,
+    min_order_amount: Option<String>,
+    max_discount_amount: Option<String>,
+}
+
+impl DiscountCols {
+    fn from(d: Option<&Discount>) -> Self {
+        DiscountCols {
+            kind: d.map(|d| d.kind.as_str()),
+            value: d.and_then(|d| d.value).map(|v| v.to_string()),
+            currency: d.and_then(|d| d.currency.clone()),
+            sku: d.and_then(|d| d.sku.clone()),
+            min_order_amount: d.and_then(|d| d.min_order_amount).map(|v| v.to_string()),
+            max_discount_amount: d.and_then(|d| d.max_discount_amount).map(|v| v.to_string()),
+        }
+    }
+}
+
+pub struct NewCoupon<'a> {
+    pub code: &'a str,
+    pub description: &'a str,
+    pub service: &'a str,
+    pub valid_from: i64, // unix secs
+    pub expires_at: i64,
+    pub owner_id: Option<&'a str>, // first holder
+    pub discount: Option<&'a Discount>,
+    pub limits: UsageLimits,
+}
+
+// Client-facing reason a coupon insert or update hit a constraint; None for anything else.
+pub fn coupon_constraint_error(e: &anyhow::Error) -> Option<ApiError> {
+    let db_err = e.downcast_ref::<sqlx::Error>()?.as_database_error()?;
+    if db_err.is_unique_violation() {
+        Some(ApiError::conflict("a coupon with this code already exists (it may be archived)"))
+    } else if db_err.is_foreign_key_violation() {
+        Some(ApiError::validation("owner_id is not a known user"))
+    } else {
+        None
+    }
+}
+
+pub async fn create_coupon(pool: &SqlitePool, c: &NewCoupon<'_>, actor: &Actor) -> Result<DbCoupon> {
+    let mut tx = pool.begin().await?;
+    let created = insert_coupon(&mut tx, c, actor).await?;
+    tx.commit().await?;
+    Ok(created)
+}
+
+// create_coupon on a caller's connection, so imports can run many of these in one transaction.
+pub async fn insert_coupon(conn: &mut SqliteConnection, c: &NewCoupon<'_>, actor: &Actor) -> Result<DbCoupon> {
     let id = Uuid::new_v4().to_string();
-    let now = Utc::now();
-    let expires_at = (now + Duration::days(expires_in_days)).timestamp();
+    let now = Utc::now().timestamp();
+    let d = DiscountCols::from(c.discount);
 
-    sqlx::query("INSERT INTO coupons(id,code,description,service,expires_at,owner_id,created_at)
-                 VALUES(?,?,?,?,?,?,?)")
+    sqlx::query("INSERT INTO coupons(id,code,description,service,valid_from,expires_at,created_at,
+                                     discount_kind,discount_value,discount_currency,discount_sku,
+                                     min_order_amount,max_discount_amount,
+                                     max_redemptions,max_redemptions_per_user,max_claims)
+                 VALUES(?,?,?,?,?,?,?,?,?,?,?,?,?,?,?,?)")
         .bind(&id)
-        .bind(code)
-        .bind(description)
-        .bind(service)
-        .bind(expires_at)
-        .bind(owner_id)
-        .bind(now.timestamp())
-        .execute(pool)
+        .bind(c.code)
+        .bind(c.description)
+        .bind(c.service)
+        .bind(c.valid_from)
+        .bind(c.expires_at)
+        .bind(now)
+        .bind(d.kind)
+        .bind(d.value)
+        .bind(d.currency)
+        .bind(d.sku)
+        .bind(d.min_order_amount)
+        .bind(d.max_discount_amount)
+        .bind(c.limits.max_redemptions)
+        .bind(c.limits.max_per_user)
+        .bind(c.limits.max_claims)
+        .execute(&mut *conn)
         .await?;
+    if let Some(owner) = c.owner_id {
+        add_claim(conn, &id, owner).await?;
+    }
 
-    Ok(DbCoupon {
+    let created = DbCoupon {
         id,
-        code: code.to_string(),
-        description: description.to_string(),
-        service: service.to_string(),
-        expires_at,
-        owner_id: owner_id.map(|s| s.to_string()),
-        created_at: now.timestamp(),
-    })
+        code: c.code.to_string(),
+        description: c.description.to_string(),
+        service: c.service.to_string(),
+        valid_from: c.valid_from,
+        expires_at: c.expires_at,
+        owner_id: c.owner_id.filter(|_| c.limits.max_claims == Some(1)).map(|s| s.to_string()),
+        created_at: now,
+        discount: c.discount.cloned(),
+        batch_id: None,
+        limits: c.limits,
+        redemption_count: 0,
+        claim_count: c.owner_id.map_or(0, |_| 1),
+        deleted_at: None,
+        deleted_by: None,
+    };
+    let after = coupon_snapshot(conn, &created).await?;
+    record_audit(conn, actor, "coupon.create", ("coupon", &created.id), None, Some(after)).await?;
+    Ok(created)
+}
+
+// ---------- Batches (bulk generation) ----------
+
+#[derive(Clone)]
+pub struct DbCouponBatch {
+    pub id: String,
+    pub pattern: String,
+    pub count: i64,
+    pub description: String,
+    pub service: String,
+    pub valid_from: i64,
+    pub expires_at: i64,
+    pub created_by: Option<String>,
+    pub created_at: i64,
+}
+
+pub struct NewCouponBatch<'a> {
+    pub pattern: &'a str,
+    pub count: usize,
+    pub description: &'a str,
+    pub service: &'a str,
+    pub valid_from: i64, // unix secs
+    pub expires_at: i64,
+    pub discount: Option<&'a Discount>,
+    pub created_by: Option<&'a str>, // None when made with an API key
 }
 
-pub async fn update_coupon_by_code(
+// Tries per code before giving up; only reachable if the pattern space is nearly full.
+const MAX_CODE_ATTEMPTS: usize = 20;
+
+// Inserts the batch and `count` coupons with codes from `next_code`, all in one transaction.
+// A code already taken (UNIQUE(code)) is simply redrawn.
+pub async fn create_coupon_batch(
     pool: &SqlitePool,
-    code: &str,
-    description: Option<&str>,
-    service: Option<&str>,
-    expires_in_days: Option<i64>,
-    owner_id: Option<Option<&str>>, // Some(Some(x)) set, Some(None) clear, None leave unchanged
-) -> Result<bool> {
-    // Fetch existing
-    let Some(cur) = get_coupon_by_code(pool, code).await? else { return Ok(false); };
+    b: &NewCouponBatch<'_>,
+    mut next_code: impl FnMut() -> String,
+    actor: &Actor,
+) -> Result<DbCouponBatch> {
+    let batch_id = Uuid::new_v4().to_string();
+    let now = Utc::now().timestamp();
+    let d = DiscountCols::from(b.discount);
+    let mut tx = pool.begin().await?;
 
-    let new_desc = description.unwrap_or(&cur.description);
-    let new_serv = service.unwrap_or(&cur.service);
-    let new_expires_at = if let Some(days) = expires_in_days {
-        (Utc::now() + Duration::days(days)).timestamp()
-    } else {
-        cur.expires_at
-    };
-    let new_owner: Option<&str> = match owner_id {
-        None => cur.owner_id.as_deref(),
-        Some(Some(v)) => Some(v),
-        Some(None) => None,
+    sqlx::query("INSERT INTO coupon_batches(id,pattern,count,description,service,valid_from,expires_at,created_by,created_at)
+                 VALUES(?,?,?,?,?,?,?,?,?)")
+        .bind(&batch_id)
+        .bind(b.pattern)
+        .bind(b.count as i64)
+        .bind(b.description)
+        .bind(b.service)
+        .bind(b.valid_from)
+        .bind(b.expires_at)
+        .bind(b.created_by)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+    for _ in 0..b.count {
+        let mut inserted = false;
+        for _ in 0..MAX_CODE_ATTEMPTS {
+            let id = Uuid::new_v4().to_string();
+            let code = next_code();
+            let n = sqlx::query("INSERT INTO coupons(id,code,description,service,valid_from,expires_at,created_at,
+                                                     discount_kind,discount_value,discount_currency,discount_sku,
+                                                     min_order_amount,max_discount_amount,batch_id)
+                                 VALUES(?,?,?,?,?,?,?,?,?,?,?,?,?,?)
+                                 ON CONFLICT(code) DO NOTHING")
+                .bind(&id)
+                .bind(&code)
+                .bind(b.description)
+                .bind(b.service)
+                .bind(b.valid_from)
+                .bind(b.expires_at)
+                .bind(now)
+                .bind(d.kind)
+                .bind(&d.value)
+                .bind(&d.currency)
+                .bind(&d.sku)
+                .bind(&d.min_order_amount)
+                .bind(&d.max_discount_amount)
+                .bind(&batch_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            if n == 1 {
+                inserted = true;
+                break;
+            }
+        }
+        if !inserted {
+            anyhow::bail!(ApiError::conflict(format!(
+                "could not find a free code after {MAX_CODE_ATTEMPTS} attempts; use a longer pattern"
+            )));
+        }
+    }
+
+    let batch = DbCouponBatch {
+        id: batch_id,
+        pattern: b.pattern.to_string(),
+        count: b.count as i64,
+        description: b.description.to_string(),
+        service: b.service.to_string(),
+        valid_from: b.valid_from,
+        expires_at: b.expires_at,
+        created_by: b.created_by.map(str::to_string),
+        created_at: now,
     };
+    // One entry for the whole batch; its coupons carry batch_id.
+    let after = json!({
+        "id": batch.id,
+        "pattern": batch.pattern,
+        "count": batch.count,
+        "description": batch.description,
+        "service": batch.service,
+        "valid_from": batch.valid_from,
+        "expires_at": batch.expires_at,
+        "discount": b.discount.map(discount_json),
+    });
+    record_audit(&mut tx, actor, "coupon.generate", ("coupon_batch", &batch.id), None, Some(after)).await?;
+    tx.commit().await?;
+    Ok(batch)
+}
+
+pub async fn get_coupon_batch(pool: &SqlitePool, id: &str) -> Result<Option<DbCouponBatch>> {
+    let row = sqlx::query("SELECT id,pattern,count,description,service,valid_from,expires_at,created_by,created_at
+                           FROM coupon_batches WHERE id=?")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.map(|r| DbCouponBatch {
+        id: r.get("id"),
+        pattern: r.get("pattern"),
+        count: r.get("count"),
+        description: r.get("description"),
+        service: r.get("service"),
+        valid_from: r.get("valid_from"),
+        expires_at: r.get("expires_at"),
+        created_by: r.get("created_by"),
+        created_at: r.get("created_at"),
+    }))
+}
+
+// Changes to a coupon; None leaves a field unchanged.
+#[derive(Default)]
+pub struct CouponPatch<'a> {
+    pub description: Option<&'a str>,
+    pub service: Option<&'a str>,
+    pub valid_from: Option<i64>,
+    pub expires_at: Option<i64>,
+    pub owner_id: Option<Option<&'a str>>, // Some(Some(x)) x becomes the only holder, Some(None) no holders
+    pub discount: Option<Option<&'a Discount>>, // Some(None) removes the discount terms
+    pub limits: UsageLimitsPatch,
+}
 
-    let n = sqlx::query("UPDATE coupons SET description=?, service=?, expires_at=?, owner_id=? WHERE code=?")
+pub async fn update_coupon_by_code(pool: &SqlitePool, code: &str, p: &CouponPatch<'_>, actor: &Actor) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    // Fetch existing
+    let Some(cur) = coupon_by_code(&mut tx, code, false).await? else { return Ok(false); };
+
+    let new_desc = p.description.unwrap_or(&cur.description);
+    let new_serv = p.service.unwrap_or(&cur.service);
+    let new_valid_from = p.valid_from.unwrap_or(cur.valid_from);
+    let new_expires_at = p.expires_at.unwrap_or(cur.expires_at);
+    if new_expires_at <= new_valid_from {
+        anyhow::bail!(ApiError::validation("Invalid validity window: expiry must be after the start"));
+    }
+    let d = DiscountCols::from(match p.discount {
+        None => cur.discount.as_ref(),
+        Some(d) => d,
+    });
+    let new_limits = p.limits.apply(cur.limits);
+    let before = coupon_snapshot(&mut tx, &cur).await?;
+
+    let n = sqlx::query("UPDATE coupons SET description=?, service=?, valid_from=?, expires_at=?,
+                           discount_kind=?, discount_value=?, discount_currency=?, discount_sku=?,
+                           min_order_amount=?, max_discount_amount=?,
+                           max_redemptions=?, max_redemptions_per_user=?, max_claims=?
+                         WHERE code=? AND deleted_at IS NULL")
         .bind(new_desc)
         .bind(new_serv)
+        .bind(new_valid_from)
         .bind(new_expires_at)
-        .bind(new_owner)
+        .bind(d.kind)
+        .bind(d.value)
+        .bind(d.currency)
+        .bind(d.sku)
+        .bind(d.min_order_amount)
+        .bind(d.max_discount_amount)
+        .bind(new_limits.max_redemptions)
+        .bind(new_limits.max_per_user)
+        .bind(new_limits.max_claims)
         .bind(code)
-        .execute(pool)
+        .execute(&mut *tx)
         .await?
         .rows_affected();
 
+    if n == 1 {
+        if let Some(owner) = p.owner_id {
+            sqlx::query("DELETE FROM coupon_claims WHERE coupon_id=?")
+                .bind(&cur.id)
+                .execute(&mut *tx)
+                .await?;
+            if let Some(owner) = owner {
+                add_claim(&mut tx, &cur.id, owner).await?;
+            }
+        }
+        if let Some(after) = coupon_by_code(&mut tx, code, false).await? {
+            let after = coupon_snapshot(&mut tx, &after).await?;
+            record_audit(&mut tx, actor, "coupon.update", ("coupon", &cur.id), Some(before), Some(after)).await?;
+        }
+    }
+    tx.commit().await?;
+
     Ok(n == 1)
 }
 
-pub async fn delete_coupon_by_code(pool: &SqlitePool, code: &str) -> Result<bool> {
-    let n = sqlx::query("DELETE FROM coupons WHERE code=?")
-        .bind(code)
-        .execute(pool)
-        .await?
-        .rows_affected();
-    Ok(n == 1)
+// Soft delete: the coupon and its claims and redemptions stay, hidden from everything but the archive.
+pub async fn archive_coupon_by_code(pool: &SqlitePool, code: &str, actor: &Actor) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let Some(cur) = coupon_by_code(&mut tx, code, false).await? else { return Ok(false); };
+    sqlx::query("UPDATE coupons SET deleted_at=?, deleted_by=? WHERE id=?")
+        .bind(Utc::now().timestamp())
+        .bind(&actor.user_id)
+        .bind(&cur.id)
+        .execute(&mut *tx)
+        .await?;
+    audit_coupon_change(&mut tx, actor, "coupon.archive", &cur, code, true).await?;
+    tx.commit().await?;
+    Ok(true)
 }
 
-pub async fn get_coupon_by_code(pool: &SqlitePool, code: &str) -> Result<Option<DbCoupon>> {
-    let row = sqlx::query("SELECT id,code,description,service,expires_at,owner_id,created_at FROM coupons WHERE code=?")
-        .bind(code)
-        .fetch_optional(pool)
+pub async fn restore_coupon_by_code(pool: &SqlitePool, code: &str, actor: &Actor) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let Some(cur) = coupon_by_code(&mut tx, code, true).await? else { return Ok(false); };
+    sqlx::query("UPDATE coupons SET deleted_at=NULL, deleted_by=NULL WHERE id=?")
+        .bind(&cur.id)
+        .execute(&mut *tx)
+        .await?;
+    audit_coupon_change(&mut tx, actor, "coupon.restore", &cur, code, false).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+// Hard delete, cascading to claims and redemptions. Only archived coupons can be purged.
+pub async fn purge_coupon_by_code(pool: &SqlitePool, code: &str, actor: &Actor) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let Some(cur) = coupon_by_code(&mut tx, code, true).await? else { return Ok(false); };
+    let before = coupon_snapshot(&mut tx, &cur).await?;
+    sqlx::query("DELETE FROM coupons WHERE id=?")
+        .bind(&cur.id)
+        .execute(&mut *tx)
         .await?;
+    record_audit(&mut tx, actor, "coupon.purge", ("coupon", &cur.id), Some(before), None).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+// Audit entry for a change to `cur`, reading the new state back from the same transaction.
+async fn audit_coupon_change(
+    conn: &mut SqliteConnection,
+    actor: &Actor,
+    action: &str,
+    cur: &DbCoupon,
+    code: &str,
+    archived_after: bool,
+) -> Result<()> {
+    let before = coupon_snapshot(conn, cur).await?;
+    let after = match coupon_by_code(conn, code, archived_after).await? {
+        Some(c) => Some(coupon_snapshot(conn, &c).await?),
+        None => None,
+    };
+    record_audit(conn, actor, action, ("coupon", &cur.id), Some(before), after).await
+}
+
+const COUPON_COLS: &str = "id,code,description,service,valid_from,expires_at,created_at,
+                           discount_kind,discount_value,discount_currency,discount_sku,
+                           min_order_amount,max_discount_amount,batch_id,
+                           max_redemptions,max_redemptions_per_user,redemption_count,max_claims,
+                           deleted_at,deleted_by,
+                           (SELECT COUNT(*) FROM coupon_claims cl WHERE cl.coupon_id = coupons.id) AS claim_count,
+                           (SELECT MIN(cl.user_id) FROM coupon_claims cl
+                             WHERE cl.coupon_id = coupons.id AND coupons.max_claims = 1) AS sole_holder_id";
+
+fn discount_from_row(r: &SqliteRow) -> Option<Discount> {
+    let decimal = |col: &str| r.get::<Option<String>,_>(col).and_then(|s| s.parse().ok());
+    let kind = DiscountKind::parse(&r.get::<Option<String>,_>("discount_kind")?)?;
+    Some(Discount {
+        kind,
+        value: decimal("discount_value"),
+        currency: r.get("discount_currency"),
+        sku: r.get("discount_sku"),
+        min_order_amount: decimal("min_order_amount"),
+        max_discount_amount: decimal("max_discount_amount"),
+    })
+}
 
-    Ok(row.map(|r| DbCoupon {
+fn coupon_from_row(r: &SqliteRow) -> DbCoupon {
+    DbCoupon {
         id: r.get("id"),
         code: r.get("code"),
         description: r.get("description"),
         service: r.get("service"),
+        valid_from: r.get("valid_from"),
         expires_at: r.get("expires_at"),
-        owner_id: r.get::<Option<String>,_>("owner_id"),
+        owner_id: r.get::<Option<String>,_>("sole_holder_id"),
         created_at: r.get("created_at"),
-    }))
+        discount: discount_from_row(r),
+        batch_id: r.get("batch_id"),
+        limits: UsageLimits {
+            max_redemptions: r.get("max_redemptions"),
+            max_per_user: r.get("max_redemptions_per_user"),
+            max_claims: r.get("max_claims"),
+        },
+        redemption_count: r.get("redemption_count"),
+        claim_count: r.get("claim_count"),
+        deleted_at: r.get("deleted_at"),
+        deleted_by: r.get("deleted_by"),
+    }
 }
 
-pub async fn list_coupons(pool: &SqlitePool, active_only: bool) -> Result<Vec<DbCoupon>> {
-    let rows = if active_only {
-        sqlx::query("SELECT id,code,description,service,expires_at,owner_id,created_at
-                     FROM coupons WHERE expires_at > ? ORDER BY created_at DESC")
-            .bind(Utc::now().timestamp())
-            .fetch_all(pool)
-            .await?
-    } else {
-        sqlx::query("SELECT id,code,description,service,expires_at,owner_id,created_at
-                     FROM coupons ORDER BY created_at DESC")
-            .fetch_all(pool)
-            .await?
-    };
+async fn coupon_by_code(conn: &mut SqliteConnection, code: &str, archived: bool) -> Result<Option<DbCoupon>> {
+    let deleted = if archived { "IS NOT NULL" } else { "IS NULL" };
+    let row = sqlx::query(&format!("SELECT {COUPON_COLS} FROM coupons WHERE code=? AND deleted_at {deleted}"))
+        .bind(code)
+        .fetch_optional(conn)
+        .await?;
 
-    Ok(rows
-        .into_iter()
-        .map(|r| DbCoupon {
-            id: r.get("id"),
-            code: r.get("code"),
-            description: r.get("description"),
-            service: r.get("service"),
-            expires_at: r.get("expires_at"),
-            owner_id: r.get::<Option<String>,_>("owner_id"),
-            created_at: r.get("created_at"),
-        })
-        .collect())
+    Ok(row.as_ref().map(coupon_from_row))
 }
 
-// User claims an unowned, non-expired coupon.
-// Returns the coupon if claim succeeded, or Ok(None) if it was already owned/expired/not found.
-pub async fn claim_coupon(pool: &SqlitePool, code: &str, user_id: &str) -> Result<Option<DbCoupon>> {
-    let now = Utc::now().timestamp();
-    let n = sqlx::query(
-        "UPDATE coupons SET owner_id=? WHERE code=? AND owner_id IS NULL AND expires_at > ?"
+// Live (not archived) coupon.
+pub async fn get_coupon_by_code(pool: &SqlitePool, code: &str) -> Result<Option<DbCoupon>> {
+    coupon_by_code(&mut *pool.acquire().await?, code, false).await
+}
+
+pub async fn get_archived_coupon_by_code(pool: &SqlitePool, code: &str) -> Result<Option<DbCoupon>> {
+    coupon_by_code(&mut *pool.acquire().await?, code, true).await
+}
+
+// Which coupons a listing covers, and in what order.
+#[derive(Clone)]
+pub struct CouponQuery {
+    pub active_only: bool,                    // valid_from <= now < expires_at
+    pub services: Vec<String>,                // any of these; empty = all
+    pub code_prefix: Option<String>,
+    pub description_contains: Option<String>, // case-insensitive substring
+    pub expires_after: Option<i64>,
+    pub expires_before: Option<i64>,
+    pub created_after: Option<i64>,
+    pub created_before: Option<i64>,
+    pub owned: Option<bool>,                  // Some(true) held by anyone, Some(false) by no one
+    pub owner_id: Option<String>,             // only coupons held by this user
+    pub batch_id: Option<String>,             // only coupons from this generated batch
+    pub archived: bool,                       // archived coupons instead of live ones
+    pub sort: CouponSortKey,
+    pub descending: bool,
+}
+
+impl Default for CouponQuery {
+    fn default() -> Self {
+        CouponQuery {
+            active_only: false,
+            services: Vec::new(),
+            code_prefix: None,
+            description_contains: None,
+            expires_after: None,
+            expires_before: None,
+            created_after: None,
+            created_before: None,
+            owned: None,
+            owner_id: None,
+            batch_id: None,
+            archived: false,
+            sort: CouponSortKey::CreatedAt,
+            descending: true, // newest first
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CouponSortKey {
+    CreatedAt,
+    ExpiresAt,
+    Code,
+    Service,
+}
+
+impl CouponSortKey {
+    pub fn column(self) -> &'static str {
+        match self {
+            CouponSortKey::CreatedAt => "created_at",
+            CouponSortKey::ExpiresAt => "expires_at",
+            CouponSortKey::Code => "code",
+            CouponSortKey::Service => "service",
+        }
+    }
+
+    pub fn from_column(s: &str) -> Option<Self> {
+        [Self::CreatedAt, Self::ExpiresAt, Self::Code, Self::Service]
+            .into_iter()
+            .find(|k| k.column() == s)
+    }
+}
+
+#[derive(Clone)]
+pub enum SortValue {
+    Int(i64),
+    Text(String),
+}
+
+// Keyset position: the sort column's value plus id as a tie-breaker,
+// so new inserts never shift a page boundary.
+#[derive(Clone)]
+pub struct CouponCursor {
+    pub sort: CouponSortKey,
+    pub value: SortValue,
+    pub id: String,
+}
+
+impl CouponCursor {
+    pub fn for_coupon(c: &DbCoupon, sort: CouponSortKey) -> Self {
+        let value = match sort {
+            CouponSortKey::CreatedAt => SortValue::Int(c.created_at),
+            CouponSortKey::ExpiresAt => SortValue::Int(c.expires_at),
+            CouponSortKey::Code => SortValue::Text(c.code.clone()),
+            CouponSortKey::Service => SortValue::Text(c.service.clone()),
+        };
+        CouponCursor { sort, value, id: c.id.clone() }
+    }
+}
+
+// Escapes LIKE wildcards; pair with `ESCAPE '\'`.
+fn like_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+// Everything here is a plain column comparison so SQLite can use the coupon indexes;
+// only the description search has to scan.
+fn push_coupon_filters(qb: &mut QueryBuilder<'_, Sqlite>, q: &CouponQuery) {
+    qb.push(if q.archived { " AND deleted_at IS NOT NULL" } else { " AND deleted_at IS NULL" });
+    if q.active_only {
+        let now = Utc::now().timestamp();
+        qb.push(" AND valid_from <= ").push_bind(now);
+        qb.push(" AND expires_at > ").push_bind(now);
+    }
+    if !q.services.is_empty() {
+        qb.push(" AND service IN (");
+        let mut sep = qb.separated(", ");
+        for s in &q.services {
+            sep.push_bind(s.clone());
+        }
+        qb.push(")");
+    }
+    if let Some(prefix) = &q.code_prefix {
+        // Range scan on the code index; LIKE 'x%' can't use it with the default collation.
+        qb.push(" AND code >= ").push_bind(prefix.clone());
+        qb.push(" AND code < ").push_bind(format!("{prefix}\u{10FFFF}"));
+    }
+    if let Some(needle) = &q.description_contains {
+        qb.push(" AND description LIKE ").push_bind(format!("%{}%", like_escape(needle))).push(" ESCAPE '\\'");
+    }
+    if let Some(t) = q.expires_after {
+        qb.push(" AND expires_at > ").push_bind(t);
+    }
+    if let Some(t) = q.expires_before {
+        qb.push(" AND expires_at < ").push_bind(t);
+    }
+    if let Some(t) = q.created_after {
+        qb.push(" AND created_at > ").push_bind(t);
+    }
+    if let Some(t) = q.created_before {
+        qb.push(" AND created_at < ").push_bind(t);
+    }
+    match q.owned {
+        Some(true) => { qb.push(" AND EXISTS (SELECT 1 FROM coupon_claims cl WHERE cl.coupon_id = coupons.id)"); }
+        Some(false) => { qb.push(" AND NOT EXISTS (SELECT 1 FROM coupon_claims cl WHERE cl.coupon_id = coupons.id)"); }
+        None => {}
+    }
+    if let Some(owner) = &q.owner_id {
+        qb.push(" AND EXISTS (SELECT 1 FROM coupon_claims cl WHERE cl.coupon_id = coupons.id AND cl.user_id = ")
+            .push_bind(owner.clone())
+            .push(")");
+    }
+    if let Some(batch) = &q.batch_id {
+        qb.push(" AND batch_id = ").push_bind(batch.clone());
+    }
+}
+
+fn push_keyset(qb: &mut QueryBuilder<'_, Sqlite>, col: &str, op: &str, c: &CouponCursor) {
+    qb.push(format!(" AND ({col}, id) {op} ("));
+    match &c.value {
+        SortValue::Int(v) => qb.push_bind(*v),
+        SortValue::Text(v) => qb.push_bind(v.clone()),
+    };
+    qb.push(", ").push_bind(c.id.clone()).push(")");
+}
+
+// One page of coupons in `q`'s order, strictly between the cursors.
+// `from_end` takes the page adjacent to `before` instead of `after` (Relay `last`).
+// The bool is true when more rows exist past the page in the direction of travel.
+pub async fn list_coupons(
+    pool: &SqlitePool,
+    q: &CouponQuery,
+    after: Option<&CouponCursor>,
+    before: Option<&CouponCursor>,
+    limit: i64,
+    from_end: bool,
+) -> Result<(Vec<DbCoupon>, bool)> {
+    if after.iter().chain(before.iter()).any(|c| c.sort != q.sort) {
+        anyhow::bail!(ApiError::validation("cursor does not match the requested sort"));
+    }
+    let col = q.sort.column();
+    let (fwd, back) = if q.descending { ("<", ">") } else { (">", "<") };
+
+    let mut qb = QueryBuilder::<Sqlite>::new(format!("SELECT {COUPON_COLS} FROM coupons WHERE 1=1"));
+    push_coupon_filters(&mut qb, q);
+    if let Some(c) = after {
+        push_keyset(&mut qb, col, fwd, c);
+    }
+    if let Some(c) = before {
+        push_keyset(&mut qb, col, back, c);
+    }
+    let dir = if q.descending != from_end { "DESC" } else { "ASC" };
+    qb.push(format!(" ORDER BY {col} {dir}, id {dir}"));
+    qb.push(" LIMIT ").push_bind(limit + 1);
+
+    let rows = qb.build().fetch_all(pool).await?;
+    let has_more = rows.len() as i64 > limit;
+    let mut page: Vec<DbCoupon> = rows.iter().take(limit as usize).map(coupon_from_row).collect();
+    if from_end {
+        page.reverse();
+    }
+    Ok((page, has_more))
+}
+
+// Every matching coupon in sort order, without paging (CSV export).
+pub async fn all_coupons(pool: &SqlitePool, q: &CouponQuery) -> Result<Vec<DbCoupon>> {
+    let col = q.sort.column();
+    let dir = if q.descending { "DESC" } else { "ASC" };
+    let mut qb = QueryBuilder::<Sqlite>::new(format!("SELECT {COUPON_COLS} FROM coupons WHERE 1=1"));
+    push_coupon_filters(&mut qb, q);
+    qb.push(format!(" ORDER BY {col} {dir}, id {dir}"));
+
+    let rows = qb.build().fetch_all(pool).await?;
+    Ok(rows.iter().map(coupon_from_row).collect())
+}
+
+pub async fn count_coupons(pool: &SqlitePool, q: &CouponQuery) -> Result<i64> {
+    let mut qb = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM coupons WHERE 1=1");
+    push_coupon_filters(&mut qb, q);
+    Ok(qb.build_query_scalar().fetch_one(pool).await?)
+}
+
+// Coupons whose expiry fell in (after, until]; used to announce expirations.
+pub async fn coupons_expired_between(pool: &SqlitePool, after: i64, until: i64) -> Result<Vec<DbCoupon>> {
+    let rows = sqlx::query(&format!("SELECT {COUPON_COLS} FROM coupons
+                                    WHERE expires_at > ? AND expires_at <= ? AND deleted_at IS NULL"))
+        .bind(after)
+        .bind(until)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows.iter().map(coupon_from_row).collect())
+}
+
+// ---------- Claims ----------
+
+// Which usage cap stopped a claim or redemption.
+#[derive(Clone, Copy, Debug)]
+pub enum LimitReached {
+    Total,   // no uses left for anyone
+    PerUser, // this user has used up their share
+}
+
+pub enum ClaimOutcome {
+    Claimed(Box<DbCoupon>),
+    LimitReached(LimitReached),
+    NotFound, // or archived
+    AlreadyClaimed,
+    NotYetValid,
+    Expired,
+    FullyClaimed, // no free holder slot
+}
+
+async fn add_claim(conn: &mut SqliteConnection, coupon_id: &str, user_id: &str) -> Result<()> {
+    sqlx::query("INSERT INTO coupon_claims(coupon_id,user_id,claimed_at) VALUES(?,?,?)")
+        .bind(coupon_id)
+        .bind(user_id)
+        .bind(Utc::now().timestamp())
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+// User claims a currently valid coupon that has a free holder slot and uses left for them.
+// One statement, so concurrent claims can't exceed max_claims.
+pub async fn claim_coupon(pool: &SqlitePool, code: &str, user_id: &str, actor: &Actor) -> Result<ClaimOutcome> {
+    let now = Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+    let n = sqlx::query(
+        "INSERT INTO coupon_claims(coupon_id,user_id,claimed_at)
+         SELECT id, ?, ? FROM coupons
+         WHERE code=? AND deleted_at IS NULL AND valid_from <= ? AND expires_at > ?
+           AND (max_claims IS NULL OR max_claims >
+                (SELECT COUNT(*) FROM coupon_claims cl WHERE cl.coupon_id = coupons.id))
+           AND (max_redemptions IS NULL OR redemption_count < max_redemptions)
+           AND (max_redemptions_per_user IS NULL OR max_redemptions_per_user >
+                (SELECT COUNT(*) FROM redemptions r WHERE r.coupon_id = coupons.id AND r.user_id = ?))
+         ON CONFLICT(coupon_id,user_id) DO NOTHING"
     )
     .bind(user_id)
+    .bind(now)
     .bind(code)
     .bind(now)
-    .execute(pool)
+    .bind(now)
+    .bind(user_id)
+    .execute(&mut *tx)
     .await?
     .rows_affected();
 
     if n == 1 {
-        get_coupon_by_code(pool, code).await
-    } else {
-        Ok(None)
+        let Some(c) = coupon_by_code(&mut tx, code, false).await? else { return Ok(ClaimOutcome::NotFound); };
+        let after = json!({ "coupon_id": c.id, "code": c.code, "user_id": user_id, "claimed_at": now });
+        record_audit(&mut tx, actor, "coupon.claim", ("coupon", &c.id), None, Some(after)).await?;
+        tx.commit().await?;
+        return Ok(ClaimOutcome::Claimed(Box::new(c)));
+    }
+    tx.rollback().await?;
+
+    // Work out why, for the error.
+    let Some(c) = get_coupon_by_code(pool, code).await? else { return Ok(ClaimOutcome::NotFound); };
+    if has_claim(pool, &c.id, user_id).await? {
+        return Ok(ClaimOutcome::AlreadyClaimed);
+    }
+    if c.valid_from > now {
+        return Ok(ClaimOutcome::NotYetValid);
     }
+    if c.expires_at <= now {
+        return Ok(ClaimOutcome::Expired);
+    }
+    Ok(match usage_limit_reached(pool, &c, user_id).await? {
+        Some(hit) => ClaimOutcome::LimitReached(hit),
+        // Also when a slot was taken and freed again in between.
+        None => ClaimOutcome::FullyClaimed,
+    })
 }
 
-// User releases a coupon they own.
-pub async fn release_coupon(pool: &SqlitePool, code: &str, user_id: &str) -> Result<bool> {
-    let n = sqlx::query("UPDATE coupons SET owner_id=NULL WHERE code=? AND owner_id=?")
-        .bind(code)
+// User gives up their claim. Used-up coupons stay with their last holders.
+pub async fn release_coupon(pool: &SqlitePool, code: &str, user_id: &str, actor: &Actor) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let released: Option<(String, i64)> = sqlx::query_as(
+        "DELETE FROM coupon_claims WHERE user_id=? AND coupon_id =
+           (SELECT id FROM coupons WHERE code=? AND deleted_at IS NULL
+              AND (max_redemptions IS NULL OR redemption_count < max_redemptions))
+         RETURNING coupon_id, claimed_at"
+    )
+    .bind(user_id)
+    .bind(code)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some((coupon_id, claimed_at)) = released else { return Ok(false); };
+    let before = json!({ "coupon_id": coupon_id, "code": code, "user_id": user_id, "claimed_at": claimed_at });
+    record_audit(&mut tx, actor, "coupon.release", ("coupon", &coupon_id), Some(before), None).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+pub async fn has_claim(pool: &SqlitePool, coupon_id: &str, user_id: &str) -> Result<bool> {
+    let one: Option<i64> = sqlx::query_scalar("SELECT 1 FROM coupon_claims WHERE coupon_id=? AND user_id=?")
+        .bind(coupon_id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(one.is_some())
+}
+
+// Users currently holding the coupon; who gets its myCouponEvents.
+pub async fn coupon_holders(pool: &SqlitePool, coupon_id: &str) -> Result<Vec<String>> {
+    Ok(sqlx::query_scalar("SELECT user_id FROM coupon_claims WHERE coupon_id=?")
+        .bind(coupon_id)
+        .fetch_all(pool)
+        .await?)
+}
+
+// ---------- Redemptions ----------
+
+#[derive(Clone)]
+pub struct DbRedemption {
+    pub id: i64,
+    pub coupon_id: String,
+    pub user_id: String,
+    pub redeemed_at: i64,
+}
+
+pub enum RedeemOutcome {
+    Redeemed(DbRedemption),
+    LimitReached(LimitReached),
+    NotFound, // or archived
+    NotClaimed,
+    NotYetValid,
+    Expired,
+}
+
+// Holder redeems a claimed coupon inside its validity window. The caps are checked and the counter bumped
+// by one conditional UPDATE, so concurrent redemptions can't overshoot a limit.
+pub async fn redeem_coupon(pool: &SqlitePool, code: &str, user_id: &str, actor: &Actor) -> Result<RedeemOutcome> {
+    let now = Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+    let coupon_id: Option<String> = sqlx::query_scalar(
+        "UPDATE coupons SET redemption_count = redemption_count + 1
+         WHERE code=? AND deleted_at IS NULL AND valid_from <= ? AND expires_at > ?
+           AND EXISTS (SELECT 1 FROM coupon_claims cl WHERE cl.coupon_id = coupons.id AND cl.user_id = ?)
+           AND (max_redemptions IS NULL OR redemption_count < max_redemptions)
+           AND (max_redemptions_per_user IS NULL OR max_redemptions_per_user >
+                (SELECT COUNT(*) FROM redemptions r WHERE r.coupon_id = coupons.id AND r.user_id = ?))
+         RETURNING id"
+    )
+    .bind(code)
+    .bind(now)
+    .bind(now)
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(coupon_id) = coupon_id else {
+        tx.rollback().await?;
+        let Some(c) = get_coupon_by_code(pool, code).await? else { return Ok(RedeemOutcome::NotFound); };
+        if c.valid_from > now {
+            return Ok(RedeemOutcome::NotYetValid);
+        }
+        if c.expires_at <= now {
+            return Ok(RedeemOutcome::Expired);
+        }
+        if !has_claim(pool, &c.id, user_id).await? {
+            return Ok(RedeemOutcome::NotClaimed);
+        }
+        return Ok(match usage_limit_reached(pool, &c, user_id).await? {
+            Some(hit) => RedeemOutcome::LimitReached(hit),
+            // Also when the claim was released in between.
+            None => RedeemOutcome::NotClaimed,
+        });
+    };
+
+    let r = sqlx::query(
+        "INSERT INTO redemptions(coupon_id,user_id,redeemed_at) VALUES(?,?,?)
+         RETURNING id,coupon_id,user_id,redeemed_at"
+    )
+    .bind(&coupon_id)
+    .bind(user_id)
+    .bind(now)
+    .fetch_one(&mut *tx)
+    .await?;
+    let r = DbRedemption {
+        id: r.get("id"),
+        coupon_id: r.get("coupon_id"),
+        user_id: r.get("user_id"),
+        redeemed_at: r.get("redeemed_at"),
+    };
+    let after = json!({
+        "redemption_id": r.id,
+        "coupon_id": r.coupon_id,
+        "code": code,
+        "user_id": r.user_id,
+        "redeemed_at": r.redeemed_at,
+    });
+    record_audit(&mut tx, actor, "coupon.redeem", ("coupon", &coupon_id), None, Some(after)).await?;
+    tx.commit().await?;
+
+    Ok(RedeemOutcome::Redeemed(r))
+}
+
+pub async fn count_user_redemptions(pool: &SqlitePool, coupon_id: &str, user_id: &str) -> Result<i64> {
+    Ok(sqlx::query_scalar("SELECT COUNT(*) FROM redemptions WHERE coupon_id=? AND user_id=?")
+        .bind(coupon_id)
+        .bind(user_id)
+        .fetch_one(pool)
+        .await?)
+}
+
+// Which cap, if any, stops `user_id` from using `c` again.
+pub async fn usage_limit_reached(pool: &SqlitePool, c: &DbCoupon, user_id: &str) -> Result<Option<LimitReached>> {
+    if c.remaining_uses() == Some(0) {
+        return Ok(Some(LimitReached::Total));
+    }
+    if let Some(per_user) = c.limits.max_per_user {
+        if count_user_redemptions(pool, &c.id, user_id).await? >= per_user {
+            return Ok(Some(LimitReached::PerUser));
+        }
+    }
+    Ok(None)
+}
+
+// ---------- Audit log ----------
+
+// Who is making a change: the signed-in user (None when anonymous), or the API key, and their address.
+#[derive(Clone, Default)]
+pub struct Actor {
+    pub user_id: Option<String>,
+    pub api_key_id: Option<String>,
+    pub ip: Option<String>,
+}
+
+pub struct DbAuditEvent {
+    pub id: i64,
+    pub actor_id: Option<String>,
+    pub api_key_id: Option<String>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: String,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub ip: Option<String>,
+    pub created_at: i64,
+}
+
+// Appends an entry on the caller's connection, so it commits or rolls back with the change itself.
+async fn record_audit(
+    conn: &mut SqliteConnection,
+    actor: &Actor,
+    action: &str,
+    (target_type, target_id): (&str, &str),
+    before: Option<Value>,
+    after: Option<Value>,
+) -> Result<()> {
+    sqlx::query("INSERT INTO audit_events(actor_id,api_key_id,action,target_type,target_id,before_json,after_json,ip,created_at)
+                 VALUES(?,?,?,?,?,?,?,?,?)")
+        .bind(&actor.user_id)
+        .bind(&actor.api_key_id)
+        .bind(action)
+        .bind(target_type)
+        .bind(target_id)
+        .bind(before.map(|v| v.to_string()))
+        .bind(after.map(|v| v.to_string()))
+        .bind(&actor.ip)
+        .bind(Utc::now().timestamp())
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+fn discount_json(d: &Discount) -> Value {
+    json!({
+        "kind": d.kind.as_str(),
+        "value": d.value.map(|v| v.to_string()),
+        "currency": d.currency,
+        "sku": d.sku,
+        "min_order_amount": d.min_order_amount.map(|v| v.to_string()),
+        "max_discount_amount": d.max_discount_amount.map(|v| v.to_string()),
+    })
+}
+
+// Coupon state as recorded in before/after, including every holder.
+async fn coupon_snapshot(conn: &mut SqliteConnection, c: &DbCoupon) -> Result<Value> {
+    let holders: Vec<String> = sqlx::query_scalar("SELECT user_id FROM coupon_claims WHERE coupon_id=? ORDER BY user_id")
+        .bind(&c.id)
+        .fetch_all(conn)
+        .await?;
+    Ok(json!({
+        "id": c.id,
+        "code": c.code,
+        "description": c.description,
+        "service": c.service,
+        "valid_from": c.valid_from,
+        "expires_at": c.expires_at,
+        "discount": c.discount.as_ref().map(discount_json),
+        "batch_id": c.batch_id,
+        "max_redemptions": c.limits.max_redemptions,
+        "max_redemptions_per_user": c.limits.max_per_user,
+        "max_claims": c.limits.max_claims,
+        "redemption_count": c.redemption_count,
+        "holders": holders,
+        "deleted_at": c.deleted_at,
+        "deleted_by": c.deleted_by,
+    }))
+}
+
+#[derive(Default)]
+pub struct AuditQuery {
+    pub actor_id: Option<String>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub created_after: Option<i64>,
+    pub created_before: Option<i64>,
+}
+
+fn push_audit_filters(qb: &mut QueryBuilder<'_, Sqlite>, q: &AuditQuery) {
+    if let Some(v) = &q.actor_id {
+        qb.push(" AND actor_id = ").push_bind(v.clone());
+    }
+    if let Some(v) = &q.action {
+        qb.push(" AND action = ").push_bind(v.clone());
+    }
+    if let Some(v) = &q.target_type {
+        qb.push(" AND target_type = ").push_bind(v.clone());
+    }
+    if let Some(v) = &q.target_id {
+        qb.push(" AND target_id = ").push_bind(v.clone());
+    }
+    if let Some(t) = q.created_after {
+        qb.push(" AND created_at > ").push_bind(t);
+    }
+    if let Some(t) = q.created_before {
+        qb.push(" AND created_at < ").push_bind(t);
+    }
+}
+
+// Newest first, keyed on id. Same paging contract as list_coupons.
+pub async fn list_audit_events(
+    pool: &SqlitePool,
+    q: &AuditQuery,
+    after: Option<i64>,
+    before: Option<i64>,
+    limit: i64,
+    from_end: bool,
+) -> Result<(Vec<DbAuditEvent>, bool)> {
+    let mut qb = QueryBuilder::<Sqlite>::new(
+        "SELECT id,actor_id,api_key_id,action,target_type,target_id,before_json,after_json,ip,created_at
+         FROM audit_events WHERE 1=1",
+    );
+    push_audit_filters(&mut qb, q);
+    if let Some(id) = after {
+        qb.push(" AND id < ").push_bind(id);
+    }
+    if let Some(id) = before {
+        qb.push(" AND id > ").push_bind(id);
+    }
+    qb.push(if from_end { " ORDER BY id ASC" } else { " ORDER BY id DESC" });
+    qb.push(" LIMIT ").push_bind(limit + 1);
+
+    let rows = qb.build().fetch_all(pool).await?;
+    let has_more = rows.len() as i64 > limit;
+    let json = |r: &SqliteRow, col: &str| r.get::<Option<String>,_>(col).and_then(|s| serde_json::from_str(&s).ok());
+    let mut page: Vec<DbAuditEvent> = rows
+        .iter()
+        .take(limit as usize)
+        .map(|r| DbAuditEvent {
+            id: r.get("id"),
+            actor_id: r.get("actor_id"),
+            api_key_id: r.get("api_key_id"),
+            action: r.get("action"),
+            target_type: r.get("target_type"),
+            target_id: r.get("target_id"),
+            before: json(r, "before_json"),
+            after: json(r, "after_json"),
+            ip: r.get("ip"),
+            created_at: r.get("created_at"),
+        })
+        .collect();
+    if from_end {
+        page.reverse();
+    }
+    Ok((page, has_more))
+}
+
+pub async fn count_audit_events(pool: &SqlitePool, q: &AuditQuery) -> Result<i64> {
+    let mut qb = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM audit_events WHERE 1=1");
+    push_audit_filters(&mut qb, q);
+    Ok(qb.build_query_scalar().fetch_one(pool).await?)
+}
+
+// ---------- Refresh tokens ----------
+
+pub enum RefreshOutcome {
+    /// The presented token was retired and a successor issued for this user.
+    Rotated { user_id: String },
+    /// The presented token had already been rotated; its whole family is now revoked.
+    Reused,
+    /// Unknown, expired or revoked token.
+    Invalid,
+}
+
+// Starts a new token family (one per login).
+pub async fn create_refresh_token(
+    pool: &SqlitePool,
+    user_id: &str,
+    token_hash: &str,
+    expires_at: i64,
+) -> Result<()> {
+    let id = Uuid::new_v4().to_string();
+    sqlx::query("INSERT INTO refresh_tokens(id,user_id,family_id,token_hash,expires_at,created_at)
+                 VALUES(?,?,?,?,?,?)")
+        .bind(&id)
         .bind(user_id)
+        .bind(&id) // the first token's id doubles as the family id
+        .bind(token_hash)
+        .bind(expires_at)
+        .bind(Utc::now().timestamp())
         .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Exchanges `old_hash` for `new_hash` within the same family.
+// Presenting a token that was already rotated revokes every token in its family.
+pub async fn rotate_refresh_token(
+    pool: &SqlitePool,
+    old_hash: &str,
+    new_hash: &str,
+    new_expires_at: i64,
+) -> Result<RefreshOutcome> {
+    let now = Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+
+    let Some(r) = sqlx::query(
+        "SELECT id,user_id,family_id,expires_at,rotated_at,revoked_at FROM refresh_tokens WHERE token_hash=?",
+    )
+    .bind(old_hash)
+    .fetch_optional(&mut *tx)
+    .await? else {
+        return Ok(RefreshOutcome::Invalid);
+    };
+
+    let id: String = r.get("id");
+    let user_id: String = r.get("user_id");
+    let family_id: String = r.get("family_id");
+
+    if r.get::<Option<i64>,_>("revoked_at").is_some() || r.get::<i64,_>("expires_at") <= now {
+        return Ok(RefreshOutcome::Invalid);
+    }
+
+    // Conditional update so two concurrent refreshes with the same token can't both win.
+    let n = sqlx::query("UPDATE refresh_tokens SET rotated_at=? WHERE id=? AND rotated_at IS NULL")
+        .bind(now)
+        .bind(&id)
+        .execute(&mut *tx)
         .await?
         .rows_affected();
-    Ok(n == 1)
-}
\ No newline at end of file
+
+    if n != 1 {
+        sqlx::query("UPDATE refresh_tokens SET revoked_at=? WHERE family_id=? AND revoked_at IS NULL")
+            .bind(now)
+            .bind(&family_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        return Ok(RefreshOutcome::Reused);
+    }
+
+    sqlx::query("INSERT INTO refresh_tokens(id,user_id,family_id,token_hash,expires_at,created_at)
+                 VALUES(?,?,?,?,?,?)")
+        .bind(Uuid::new_v4().to_string())
+        .bind(&user_id)
+        .bind(&family_id)
+        .bind(new_hash)
+        .bind(new_expires_at)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(RefreshOutcome::Rotated { user_id })
+}
+
+// Revokes every token in the family of `token_hash`, if it belongs to `user_id`.
+pub async fn revoke_refresh_family(pool: &SqlitePool, token_hash: &str, user_id: &str) -> Result<bool> {
+    let n = sqlx::query(
+        "UPDATE refresh_tokens SET revoked_at=?
+         WHERE family_id=(SELECT family_id FROM refresh_tokens WHERE token_hash=? AND user_id=?)
+           AND revoked_at IS NULL",
+    )
+    .bind(Utc::now().timestamp())
+    .bind(token_hash)
+    .bind(user_id)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(n > 0)
+}
+
+// ---------- Password resets ----------
+
+// Replaces any reset the user already has pending, so only the latest email works.
+pub async fn create_password_reset(pool: &SqlitePool, user_id: &str, token_hash: &str, expires_at: i64) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM password_resets WHERE user_id=? AND used_at IS NULL")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("INSERT INTO password_resets(token_hash,user_id,expires_at,created_at) VALUES(?,?,?,?)")
+        .bind(token_hash)
+        .bind(user_id)
+        .bind(expires_at)
+        .bind(Utc::now().timestamp())
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// The account a reset token belongs to, if the token is still usable.
+pub async fn password_reset_user(pool: &SqlitePool, token_hash: &str) -> Result<Option<DbUser>> {
+    let row = sqlx::query(&format!(
+        "SELECT {USER_COLS} FROM users WHERE id =
+           (SELECT user_id FROM password_resets WHERE token_hash=? AND used_at IS NULL AND expires_at>?)"
+    ))
+    .bind(token_hash)
+    .bind(Utc::now().timestamp())
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.as_ref().map(user_from_row))
+}
+
+// Uses up the token and sets the new password, ending every session the user has.
+// Returns the user id, or None if the token is unknown, used or expired.
+pub async fn reset_password(
+    pool: &SqlitePool,
+    token_hash: &str,
+    password_hash: &str,
+    actor: &Actor,
+) -> Result<Option<String>> {
+    let now = Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+
+    // Conditional update so the same token can't be used twice concurrently.
+    let Some(user_id) = sqlx::query_scalar::<_, String>(
+        "UPDATE password_resets SET used_at=? WHERE token_hash=? AND used_at IS NULL AND expires_at>?
+         RETURNING user_id",
+    )
+    .bind(now)
+    .bind(token_hash)
+    .bind(now)
+    .fetch_optional(&mut *tx)
+    .await? else {
+        return Ok(None);
+    };
+
+    sqlx::query("UPDATE users SET password_hash=?, sessions_valid_after=? WHERE id=?")
+        .bind(password_hash)
+        .bind(Utc::now().timestamp_millis())
+        .bind(&user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE refresh_tokens SET revoked_at=? WHERE user_id=? AND revoked_at IS NULL")
+        .bind(now)
+        .bind(&user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let actor = Actor { user_id: Some(user_id.clone()), ip: actor.ip.clone(), ..Default::default() };
+    record_audit(&mut tx, &actor, "user.password_reset", ("user", &user_id), None, None).await?;
+    tx.commit().await?;
+    Ok(Some(user_id))
+}
+
+pub async fn prune_password_resets(pool: &SqlitePool) -> Result<u64> {
+    let n = sqlx::query("DELETE FROM password_resets WHERE expires_at <= ?")
+        .bind(Utc::now().timestamp())
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(n)
+}
+
+// ---------- Email verification ----------
+
+// Replaces any verification the user already has pending, so only the latest email works.
+pub async fn create_email_verification(pool: &SqlitePool, user_id: &str, token_hash: &str, expires_at: i64) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM email_verifications WHERE user_id=? AND used_at IS NULL")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("INSERT INTO email_verifications(token_hash,user_id,expires_at,created_at) VALUES(?,?,?,?)")
+        .bind(token_hash)
+        .bind(user_id)
+        .bind(expires_at)
+        .bind(Utc::now().timestamp())
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// Uses up the token and marks the address verified.
+// Returns the user id, or None if the token is unknown, used or expired.
+pub async fn verify_email(pool: &SqlitePool, token_hash: &str, actor: &Actor) -> Result<Option<String>> {
+    let now = Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+
+    let Some(user_id) = sqlx::query_scalar::<_, String>(
+        "UPDATE email_verifications SET used_at=? WHERE token_hash=? AND used_at IS NULL AND expires_at>?
+         RETURNING user_id",
+    )
+    .bind(now)
+    .bind(token_hash)
+    .bind(now)
+    .fetch_optional(&mut *tx)
+    .await? else {
+        return Ok(None);
+    };
+
+    sqlx::query("UPDATE users SET email_verified_at=COALESCE(email_verified_at, ?) WHERE id=?")
+        .bind(now)
+        .bind(&user_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let actor = Actor { user_id: Some(user_id.clone()), ip: actor.ip.clone(), ..Default::default() };
+    record_audit(&mut tx, &actor, "user.verify_email", ("user", &user_id), None, None).await?;
+    tx.commit().await?;
+    Ok(Some(user_id))
+}
+
+pub async fn prune_email_verifications(pool: &SqlitePool) -> Result<u64> {
+    let n = sqlx::query("DELETE FROM email_verifications WHERE expires_at <= ?")
+        .bind(Utc::now().timestamp())
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(n)
+}
+
+// ---------- Login throttling ----------
+
+// Some(unix seconds) while `subject` is locked out.
+pub async fn login_locked_until(pool: &SqlitePool, throttle: &LoginThrottle, subject: &str) -> Result<Option<i64>> {
+    let until: Option<Option<i64>> =
+        sqlx::query_scalar("SELECT locked_until FROM login_failures WHERE scope=? AND subject=?")
+            .bind(throttle.scope)
+            .bind(subject)
+            .fetch_optional(pool)
+            .await?;
+    Ok(until.flatten().filter(|&t| t > Utc::now().timestamp()))
+}
+
+pub enum LoginAttempt {
+    Allowed(Option<i64>), // the lockout end, if this attempt started one
+    Refused(i64),         // locked out until then
+}
+
+// Counts an attempt as a failure before the credentials are checked, in one statement, so
+// concurrent guesses can't all get in under the limit; `release_login_attempt` takes it back
+// if they turn out right. The lock doubles with every failure past `free_attempts`.
+pub async fn reserve_login_attempt(pool: &SqlitePool, throttle: &LoginThrottle, subject: &str) -> Result<LoginAttempt> {
+    let now = Utc::now().timestamp();
+    let failures = "CASE WHEN last_failure_at > ?4 THEN failures + 1 ELSE 1 END";
+    let lock = |n: &str| format!("CASE WHEN {n} > ?5 THEN ?3 + MIN(?6 << MIN({n} - ?5 - 1, 20), ?7) END");
+    let reserved: Option<Option<i64>> = sqlx::query_scalar(&format!(
+        "INSERT INTO login_failures(scope,subject,failures,last_failure_at,locked_until) VALUES(?1,?2,1,?3,{})
+         ON CONFLICT(scope,subject) DO UPDATE
+           SET failures={failures}, last_failure_at=?3, locked_until={}
+           WHERE locked_until IS NULL OR locked_until <= ?3
+         RETURNING locked_until",
+        lock("1"),
+        lock(failures),
+    ))
+    .bind(throttle.scope)
+    .bind(subject)
+    .bind(now)
+    .bind(now - LOGIN_FAILURE_WINDOW_SECS)
+    .bind(throttle.free_attempts)
+    .bind(throttle.base_lock_secs)
+    .bind(throttle.max_lock_secs)
+    .fetch_optional(pool)
+    .await?;
+    Ok(match reserved {
+        Some(locked_until) => LoginAttempt::Allowed(locked_until),
+        // The lock may have run out in between; then just retry in a second.
+        None => LoginAttempt::Refused(login_locked_until(pool, throttle, subject).await?.unwrap_or(now + 1)),
+    })
+}
+
+// Undoes a `reserve_login_attempt` whose credentials were right, along with any lock it started.
+pub async fn release_login_attempt(
+    pool: &SqlitePool,
+    throttle: &LoginThrottle,
+    subject: &str,
+    reserved_lock: Option<i64>,
+) -> Result<()> {
+    sqlx::query(
+        "UPDATE login_failures
+            SET failures=MAX(failures - 1, 0),
+                locked_until=CASE WHEN locked_until = ? THEN NULL ELSE locked_until END
+          WHERE scope=? AND subject=?",
+    )
+    .bind(reserved_lock)
+    .bind(throttle.scope)
+    .bind(subject)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn clear_login_failures(pool: &SqlitePool, throttle: &LoginThrottle, subject: &str) -> Result<()> {
+    sqlx::query("DELETE FROM login_failures WHERE scope=? AND subject=?")
+        .bind(throttle.scope)
+        .bind(subject)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Staff override: forgets the account's failed logins. False if unknown or had none.
+pub async fn unlock_account(pool: &SqlitePool, user_id: &str, actor: &Actor) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let Some(u) = user_by_id(&mut tx, user_id).await? else { return Ok(false); };
+    let n = sqlx::query("DELETE FROM login_failures WHERE scope=? AND subject=?")
+        .bind(ACCOUNT_THROTTLE.scope)
+        .bind(canonical_email(&u.email))
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if n == 0 {
+        return Ok(false);
+    }
+    record_audit(&mut tx, actor, "user.unlock", ("user", user_id), None, None).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+pub async fn prune_login_failures(pool: &SqlitePool) -> Result<u64> {
+    let now = Utc::now().timestamp();
+    let n = sqlx::query("DELETE FROM login_failures WHERE last_failure_at <= ? AND COALESCE(locked_until, 0) <= ?")
+        .bind(now - LOGIN_FAILURE_WINDOW_SECS)
+        .bind(now)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(n)
+}
+
+// ---------- Two-factor authentication ----------
+
+pub struct DbTotp {
+    pub secret: String,
+    pub enabled: bool, // false while enrollment awaits its first code
+    pub last_step: Option<i64>,
+}
+
+pub async fn get_totp(pool: &SqlitePool, user_id: &str) -> Result<Option<DbTotp>> {
+    let row = sqlx::query("SELECT totp_secret,totp_enabled_at,totp_last_step FROM users WHERE id=? AND totp_secret IS NOT NULL")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.map(|r| DbTotp {
+        secret: r.get("totp_secret"),
+        enabled: r.get::<Option<i64>, _>("totp_enabled_at").is_some(),
+        last_step: r.get("totp_last_step"),
+    }))
+}
+
+// Stores a new secret awaiting confirmation, replacing an unconfirmed one.
+// False if two-factor is already on.
+pub async fn start_totp_enrollment(pool: &SqlitePool, user_id: &str, secret: &str) -> Result<bool> {
+    let n = sqlx::query("UPDATE users SET totp_secret=?, totp_last_step=NULL WHERE id=? AND totp_enabled_at IS NULL")
+        .bind(secret)
+        .bind(user_id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(n > 0)
+}
+
+// Turns two-factor on with the step of the confirming code and a fresh set of recovery codes.
+// False if there's no pending enrollment (or it's already on).
+pub async fn enable_totp(
+    pool: &SqlitePool,
+    user_id: &str,
+    step: i64,
+    recovery_code_hashes: &[String],
+    actor: &Actor,
+) -> Result<bool> {
+    let now = Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+    let n = sqlx::query(
+        "UPDATE users SET totp_enabled_at=?, totp_last_step=?
+         WHERE id=? AND totp_secret IS NOT NULL AND totp_enabled_at IS NULL",
+    )
+    .bind(now)
+    .bind(step)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if n == 0 {
+        return Ok(false);
+    }
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id=?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    for hash in recovery_code_hashes {
+        sqlx::query("INSERT INTO recovery_codes(id,user_id,code_hash,created_at) VALUES(?,?,?,?)")
+            .bind(Uuid::new_v4().to_string())
+            .bind(user_id)
+            .bind(hash)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+    }
+    record_audit(&mut tx, actor, "user.totp_enable", ("user", user_id), None, None).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+// Forgets the secret, recovery codes and pending logins. False if two-factor wasn't on.
+pub async fn disable_totp(pool: &SqlitePool, user_id: &str, actor: &Actor) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let n = sqlx::query(
+        "UPDATE users SET totp_secret=NULL, totp_enabled_at=NULL, totp_last_step=NULL
+         WHERE id=? AND totp_enabled_at IS NOT NULL",
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if n == 0 {
+        return Ok(false);
+    }
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id=?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM mfa_challenges WHERE user_id=?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    record_audit(&mut tx, actor, "user.totp_disable", ("user", user_id), None, None).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+// Conditional, so a code can't be used twice, even by two requests at once.
+pub async fn use_totp_step(pool: &SqlitePool, user_id: &str, step: i64) -> Result<bool> {
+    let n = sqlx::query(
+        "UPDATE users SET totp_last_step=? WHERE id=? AND (totp_last_step IS NULL OR totp_last_step < ?)",
+    )
+    .bind(step)
+    .bind(user_id)
+    .bind(step)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    Ok(n > 0)
+}
+
+// (id, argon2 hash) of each recovery code not used yet.
+pub async fn unused_recovery_codes(pool: &SqlitePool, user_id: &str) -> Result<Vec<(String, String)>> {
+    Ok(sqlx::query_as("SELECT id,code_hash FROM recovery_codes WHERE user_id=? AND used_at IS NULL")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?)
+}
+
+// False if the code was used in the meantime.
+pub async fn use_recovery_code(pool: &SqlitePool, id: &str, user_id: &str, actor: &Actor) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let n = sqlx::query("UPDATE recovery_codes SET used_at=? WHERE id=? AND user_id=? AND used_at IS NULL")
+        .bind(Utc::now().timestamp())
+        .bind(id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if n == 0 {
+        // Right away, not on drop, so the connection doesn't go back to the pool holding a write lock.
+        tx.rollback().await?;
+        return Ok(false);
+    }
+    let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM recovery_codes WHERE user_id=? AND used_at IS NULL")
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    record_audit(&mut tx, actor, "user.recovery_code_use", ("user", user_id), None, Some(json!({ "remaining": left }))).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+pub async fn create_mfa_challenge(pool: &SqlitePool, user_id: &str, token_hash: &str, expires_at: i64) -> Result<()> {
+    sqlx::query("INSERT INTO mfa_challenges(token_hash,user_id,expires_at,created_at) VALUES(?,?,?,?)")
+        .bind(token_hash)
+        .bind(user_id)
+        .bind(expires_at)
+        .bind(Utc::now().timestamp())
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// Takes one of a pending login's attempts before its code is checked, in one statement, so
+// parallel guesses can't all get past the limit. The user it belongs to, or None once it's
+// expired or out of attempts.
+pub async fn reserve_mfa_attempt(pool: &SqlitePool, token_hash: &str) -> Result<Option<String>> {
+    Ok(sqlx::query_scalar(
+        "UPDATE mfa_challenges SET attempts = attempts + 1
+         WHERE token_hash=? AND attempts<? AND expires_at>?
+         RETURNING user_id",
+    )
+    .bind(token_hash)
+    .bind(MFA_MAX_ATTEMPTS)
+    .bind(Utc::now().timestamp())
+    .fetch_optional(pool)
+    .await?)
+}
+
+// Uses up a pending login. False if it was already used. Its last attempt may have been the
+// one that passed, so the attempt count doesn't matter here.
+pub async fn consume_mfa_challenge(pool: &SqlitePool, token_hash: &str) -> Result<bool> {
+    let n = sqlx::query("DELETE FROM mfa_challenges WHERE token_hash=? AND expires_at>?")
+        .bind(token_hash)
+        .bind(Utc::now().timestamp())
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(n > 0)
+}
+
+pub async fn prune_mfa_challenges(pool: &SqlitePool) -> Result<u64> {
+    let n = sqlx::query("DELETE FROM mfa_challenges WHERE expires_at <= ? OR attempts >= ?")
+        .bind(Utc::now().timestamp())
+        .bind(MFA_MAX_ATTEMPTS)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(n)
+}
+
+// ---------- API keys ----------
+
+#[derive(Clone)]
+pub struct DbApiKey {
+    pub id: String,
+    pub name: String,
+    pub key_prefix: String,
+    pub services: Vec<String>,
+    pub permissions: Vec<String>,
+    pub created_by: Option<String>,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+}
+
+const API_KEY_COLS: &str = "id,name,key_prefix,created_by,created_at,last_used_at,revoked_at";
+
+async fn api_key_from_row(conn: &mut SqliteConnection, r: &SqliteRow) -> Result<DbApiKey> {
+    let id: String = r.get("id");
+    let services = sqlx::query_scalar("SELECT service FROM api_key_services WHERE api_key_id=? ORDER BY service")
+        .bind(&id)
+        .fetch_all(&mut *conn)
+        .await?;
+    let permissions = sqlx::query_scalar("SELECT permission FROM api_key_permissions WHERE api_key_id=? ORDER BY permission")
+        .bind(&id)
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(DbApiKey {
+        id,
+        name: r.get("name"),
+        key_prefix: r.get("key_prefix"),
+        services,
+        permissions,
+        created_by: r.get("created_by"),
+        created_at: r.get("created_at"),
+        last_used_at: r.get("last_used_at"),
+        revoked_at: r.get("revoked_at"),
+    })
+}
+
+pub async fn create_api_key(
+    pool: &SqlitePool,
+    name: &str,
+    key_hash: &str,
+    key_prefix: &str,
+    services: &[String],
+    permissions: &[Permission],
+    actor: &Actor,
+) -> Result<DbApiKey> {
+    let id = Uuid::new_v4().to_string();
+    let now = Utc::now().timestamp();
+    let mut tx = pool.begin().await?;
+    sqlx::query("INSERT INTO api_keys(id,name,key_hash,key_prefix,created_by,created_at) VALUES(?,?,?,?,?,?)")
+        .bind(&id)
+        .bind(name)
+        .bind(key_hash)
+        .bind(key_prefix)
+        .bind(&actor.user_id)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+    for service in services {
+        sqlx::query("INSERT OR IGNORE INTO api_key_services(api_key_id,service) VALUES(?,?)")
+            .bind(&id)
+            .bind(service)
+            .execute(&mut *tx)
+            .await?;
+    }
+    for p in permissions {
+        sqlx::query("INSERT OR IGNORE INTO api_key_permissions(api_key_id,permission) VALUES(?,?)")
+            .bind(&id)
+            .bind(p.as_str())
+            .execute(&mut *tx)
+            .await?;
+    }
+    let row = sqlx::query(&format!("SELECT {API_KEY_COLS} FROM api_keys WHERE id=?"))
+        .bind(&id)
+        .fetch_one(&mut *tx)
+        .await?;
+    let key = api_key_from_row(&mut tx, &row).await?;
+    let after = json!({ "name": key.name, "key_prefix": key.key_prefix, "services": key.services, "permissions": key.permissions });
+    record_audit(&mut tx, actor, "api_key.create", ("api_key", &id), None, Some(after)).await?;
+    tx.commit().await?;
+    Ok(key)
+}
+
+// Newest first.
+pub async fn list_api_keys(pool: &SqlitePool, include_revoked: bool) -> Result<Vec<DbApiKey>> {
+    let mut conn = pool.acquire().await?;
+    let rows = sqlx::query(&format!(
+        "SELECT {API_KEY_COLS} FROM api_keys WHERE ? OR revoked_at IS NULL ORDER BY created_at DESC, id DESC"
+    ))
+    .bind(include_revoked)
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut keys = Vec::with_capacity(rows.len());
+    for r in &rows {
+        keys.push(api_key_from_row(&mut conn, r).await?);
+    }
+    Ok(keys)
+}
+
+// Only keys that haven't been revoked.
+pub async fn api_key_by_hash(pool: &SqlitePool, key_hash: &str) -> Result<Option<DbApiKey>> {
+    let mut conn = pool.acquire().await?;
+    let row = sqlx::query(&format!("SELECT {API_KEY_COLS} FROM api_keys WHERE key_hash=? AND revoked_at IS NULL"))
+        .bind(key_hash)
+        .fetch_optional(&mut *conn)
+        .await?;
+    match row {
+        Some(r) => Ok(Some(api_key_from_row(&mut conn, &r).await?)),
+        None => Ok(None),
+    }
+}
+
+// At most one write a minute per key, however busy it is.
+pub async fn touch_api_key(pool: &SqlitePool, id: &str) -> Result<()> {
+    let now = Utc::now().timestamp();
+    sqlx::query("UPDATE api_keys SET last_used_at=? WHERE id=? AND (last_used_at IS NULL OR last_used_at <= ?)")
+        .bind(now)
+        .bind(id)
+        .bind(now - 60)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+// False if unknown or already revoked.
+pub async fn revoke_api_key(pool: &SqlitePool, id: &str, actor: &Actor) -> Result<bool> {
+    let mut tx = pool.begin().await?;
+    let n = sqlx::query("UPDATE api_keys SET revoked_at=? WHERE id=? AND revoked_at IS NULL")
+        .bind(Utc::now().timestamp())
+        .bind(id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if n == 0 {
+        return Ok(false);
+    }
+    record_audit(&mut tx, actor, "api_key.revoke", ("api_key", id), None, None).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+// ---------- Revoked access tokens (jti denylist) ----------
+
+pub async fn revoke_token(pool: &SqlitePool, jti: &str, user_id: &str, expires_at: i64) -> Result<()> {
+    sqlx::query("INSERT OR IGNORE INTO revoked_tokens(jti,user_id,expires_at,revoked_at) VALUES(?,?,?,?)")
+        .bind(jti)
+        .bind(user_id)
+        .bind(expires_at)
+        .bind(Utc::now().timestamp())
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn is_token_revoked(pool: &SqlitePool, jti: &str) -> Result<bool> {
+    let one: Option<i64> = sqlx::query_scalar("SELECT 1 FROM revoked_tokens WHERE jti=?")
+        .bind(jti)
+        .fetch_optional(pool)
+        .await?;
+    Ok(one.is_some())
+}
+
+// Entries for tokens that have expired anyway are no longer needed.
+pub async fn prune_revoked_tokens(pool: &SqlitePool) -> Result<u64> {
+    let n = sqlx::query("DELETE FROM revoked_tokens WHERE expires_at <= ?")
+        .bind(Utc::now().timestamp())
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(n)
+}
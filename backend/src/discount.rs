@@ -0,0 +1,299 @@
+use anyhow::{bail, Result};
+use rust_decimal::{Decimal, RoundingStrategy};
+
+// ---------- Discount model ----------
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DiscountKind {
+    Percentage,   // value = percent off (0, 100]
+    FixedAmount,  // value = amount off, in `currency`
+    FreeShipping, // no value
+    FreeItem,     // `sku` is free; value = quantity (default 1)
+}
+
+impl DiscountKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DiscountKind::Percentage => "percentage",
+            DiscountKind::FixedAmount => "fixed_amount",
+            DiscountKind::FreeShipping => "free_shipping",
+            DiscountKind::FreeItem => "free_item",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        [Self::Percentage, Self::FixedAmount, Self::FreeShipping, Self::FreeItem]
+            .into_iter()
+            .find(|k| k.as_str() == s)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Discount {
+    pub kind: DiscountKind,
+    pub value: Option<Decimal>,
+    pub currency: Option<String>,            // ISO 4217, required for any money amount
+    pub sku: Option<String>,                 // FreeItem only
+    pub min_order_amount: Option<Decimal>,   // order subtotal must reach this
+    pub max_discount_amount: Option<Decimal>, // cap on the amount taken off
+}
+
+const MAX_SCALE: u32 = 4;
+
+impl Discount {
+    // Checks the fields make sense for `kind` and normalises them
+    // (currency upper-cased, FreeItem quantity defaulted).
+    pub fn validate(mut self) -> Result<Self> {
+        self.currency = self.currency.map(|c| c.trim().to_ascii_uppercase());
+        if let Some(c) = &self.currency {
+            if c.len() != 3 || !c.bytes().all(|b| b.is_ascii_uppercase()) {
+                bail!("Invalid discount: currency must be a 3-letter ISO 4217 code");
+            }
+        }
+
+        match self.kind {
+            DiscountKind::Percentage => {
+                let Some(v) = self.value else { bail!("Invalid discount: percentage requires a value"); };
+                if v <= Decimal::ZERO || v > Decimal::ONE_HUNDRED {
+                    bail!("Invalid discount: percentage must be greater than 0 and at most 100");
+                }
+            }
+            DiscountKind::FixedAmount => {
+                let Some(v) = self.value else { bail!("Invalid discount: fixed amount requires a value"); };
+                if v <= Decimal::ZERO {
+                    bail!("Invalid discount: fixed amount must be positive");
+                }
+                if self.currency.is_none() {
+                    bail!("Invalid discount: fixed amount requires a currency");
+                }
+            }
+            DiscountKind::FreeShipping => {
+                if self.value.is_some() {
+                    bail!("Invalid discount: free shipping takes no value");
+                }
+            }
+            DiscountKind::FreeItem => {
+                if self.sku.as_deref().is_none_or(|s| s.trim().is_empty()) {
+                    bail!("Invalid discount: free item requires a sku");
+                }
+                let qty = self.value.unwrap_or(Decimal::ONE);
+                if qty <= Decimal::ZERO || !qty.fract().is_zero() {
+                    bail!("Invalid discount: free item quantity must be a positive whole number");
+                }
+                self.value = Some(qty);
+            }
+        }
+        if self.kind != DiscountKind::FreeItem && self.sku.is_some() {
+            bail!("Invalid discount: sku only applies to free items");
+        }
+
+        if let Some(min) = self.min_order_amount {
+            if min < Decimal::ZERO {
+                bail!("Invalid discount: minimum order amount can't be negative");
+            }
+        }
+        if let Some(max) = self.max_discount_amount {
+            if max <= Decimal::ZERO {
+                bail!("Invalid discount: maximum discount must be positive");
+            }
+        }
+        if (self.min_order_amount.is_some() || self.max_discount_amount.is_some()) && self.currency.is_none() {
+            bail!("Invalid discount: order limits require a currency");
+        }
+
+        let amounts = [self.value, self.min_order_amount, self.max_discount_amount];
+        if amounts.iter().flatten().any(|d| d.normalize().scale() > MAX_SCALE) {
+            bail!("Invalid discount: at most {MAX_SCALE} decimal places");
+        }
+        Ok(self)
+    }
+}
+
+// ---------- Pricing ----------
+
+#[derive(Clone, Debug)]
+pub struct LineItem {
+    pub sku: String,
+    pub quantity: u32,
+    pub unit_price: Decimal,
+}
+
+// Why a valid coupon still doesn't apply to a particular order.
+#[derive(Debug)]
+pub enum NotApplicable {
+    CurrencyMismatch { expected: String },
+    MinimumNotMet { minimum: Decimal },
+    ItemNotInCart { sku: String },
+}
+
+// Upper bound on an order's value; keeps every intermediate product far from Decimal's limits.
+pub const MAX_ORDER_AMOUNT: Decimal = Decimal::from_parts(0xA4C6_8000, 0x3_8D7E, 0, false, 0); // 10^15
+
+// Sum of quantity * unit price; None on overflow.
+pub fn subtotal(items: &[LineItem]) -> Option<Decimal> {
+    items.iter().try_fold(Decimal::ZERO, |acc, i| {
+        acc.checked_add(i.unit_price.checked_mul(Decimal::from(i.quantity))?)
+    })
+}
+
+// Amounts are rounded to the precision the prices were given in
+// (e.g. cents for "9.99", whole units for "1000").
+fn money_scale(items: &[LineItem], shipping: Decimal) -> u32 {
+    items.iter().map(|i| i.unit_price.scale()).chain([shipping.scale()]).max().unwrap_or(0)
+}
+
+impl Discount {
+    // Amount taken off an order priced in `currency`. Never more than the part of the
+    // order it applies to: shipping for FreeShipping, the item subtotal for everything else.
+    // `subtotal` is `subtotal(items)`, already checked against MAX_ORDER_AMOUNT by the caller.
+    pub fn amount_off(
+        &self,
+        currency: &str,
+        items: &[LineItem],
+        subtotal: Decimal,
+        shipping: Decimal,
+    ) -> Result<Decimal, NotApplicable> {
+        if let Some(expected) = &self.currency {
+            if !expected.eq_ignore_ascii_case(currency) {
+                return Err(NotApplicable::CurrencyMismatch { expected: expected.clone() });
+            }
+        }
+        if let Some(minimum) = self.min_order_amount {
+            if subtotal < minimum {
+                return Err(NotApplicable::MinimumNotMet { minimum });
+            }
+        }
+
+        let scale = money_scale(items, shipping);
+        let raw = match self.kind {
+            DiscountKind::Percentage => {
+                let pct = self.value.unwrap_or(Decimal::ZERO);
+                (subtotal * pct / Decimal::ONE_HUNDRED)
+                    .round_dp_with_strategy(scale, RoundingStrategy::MidpointAwayFromZero)
+            }
+            DiscountKind::FixedAmount => self.value.unwrap_or(Decimal::ZERO),
+            DiscountKind::FreeShipping => shipping,
+            DiscountKind::FreeItem => {
+                let sku = self.sku.as_deref().unwrap_or_default();
+                // If the sku appears on several lines, the cheapest units go free.
+                let mut lines: Vec<&LineItem> = items.iter().filter(|i| i.sku == sku).collect();
+                if lines.is_empty() {
+                    return Err(NotApplicable::ItemNotInCart { sku: sku.to_string() });
+                }
+                lines.sort_by_key(|i| i.unit_price);
+                let mut remaining = self.value.unwrap_or(Decimal::ONE);
+                let mut off = Decimal::ZERO;
+                for line in lines {
+                    let qty = remaining.min(Decimal::from(line.quantity));
+                    off += qty * line.unit_price;
+                    remaining -= qty;
+                    if remaining.is_zero() {
+                        break;
+                    }
+                }
+                off
+            }
+        };
+
+        let capped = match self.max_discount_amount {
+            Some(cap) => raw.min(cap),
+            None => raw,
+        };
+        let ceiling = match self.kind {
+            DiscountKind::FreeShipping => shipping,
+            _ => subtotal,
+        };
+        Ok(capped.min(ceiling).max(Decimal::ZERO))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    fn discount(kind: DiscountKind, value: Option<&str>) -> Discount {
+        Discount {
+            kind,
+            value: value.map(d),
+            currency: Some("USD".into()),
+            sku: None,
+            min_order_amount: None,
+            max_discount_amount: None,
+        }
+    }
+
+    fn item(sku: &str, quantity: u32, unit_price: &str) -> LineItem {
+        LineItem { sku: sku.into(), quantity, unit_price: d(unit_price) }
+    }
+
+    fn off(disc: &Discount, items: &[LineItem], shipping: &str) -> Result<Decimal, NotApplicable> {
+        disc.amount_off("USD", items, subtotal(items).unwrap(), d(shipping))
+    }
+
+    #[test]
+    fn percentage_rounds_half_away_from_zero_at_price_precision() {
+        let items = [item("a", 1, "9.99")];
+        assert_eq!(off(&discount(DiscountKind::Percentage, Some("15")), &items, "0").unwrap(), d("1.50"));
+        let items = [item("a", 1, "0.05")];
+        assert_eq!(off(&discount(DiscountKind::Percentage, Some("10")), &items, "0.00").unwrap(), d("0.01"));
+        let items = [item("a", 3, "1000")];
+        assert_eq!(off(&discount(DiscountKind::Percentage, Some("12.5")), &items, "0").unwrap(), d("375"));
+    }
+
+    #[test]
+    fn max_discount_amount_caps_the_result() {
+        let mut disc = discount(DiscountKind::Percentage, Some("50"));
+        disc.max_discount_amount = Some(d("20"));
+        assert_eq!(off(&disc, &[item("a", 1, "100.00")], "0").unwrap(), d("20"));
+        assert_eq!(off(&disc, &[item("a", 1, "30.00")], "0").unwrap(), d("15.00"));
+    }
+
+    #[test]
+    fn minimum_order_amount_is_checked_against_the_subtotal() {
+        let mut disc = discount(DiscountKind::FixedAmount, Some("5"));
+        disc.min_order_amount = Some(d("50"));
+        // Shipping doesn't count towards the minimum.
+        assert!(matches!(
+            off(&disc, &[item("a", 1, "40.00")], "15.00"),
+            Err(NotApplicable::MinimumNotMet { .. })
+        ));
+        assert_eq!(off(&disc, &[item("a", 1, "50.00")], "0").unwrap(), d("5"));
+    }
+
+    #[test]
+    fn only_free_shipping_touches_shipping() {
+        let items = [item("a", 2, "10.00")];
+        assert_eq!(off(&discount(DiscountKind::FixedAmount, Some("30")), &items, "10.00").unwrap(), d("20.00"));
+        assert_eq!(off(&discount(DiscountKind::Percentage, Some("100")), &items, "10.00").unwrap(), d("20.00"));
+        assert_eq!(off(&discount(DiscountKind::FreeShipping, None), &items, "10.00").unwrap(), d("10.00"));
+    }
+
+    #[test]
+    fn free_item_takes_the_cheapest_units_across_lines() {
+        let mut disc = discount(DiscountKind::FreeItem, Some("3"));
+        disc.sku = Some("tee".into());
+        let items = [item("tee", 2, "12.00"), item("mug", 1, "1.00"), item("tee", 2, "8.00")];
+        assert_eq!(off(&disc, &items, "0").unwrap(), d("28.00"));
+
+        // Asking for more than the cart holds just frees what's there.
+        disc.value = Some(d("10"));
+        assert_eq!(off(&disc, &items, "0").unwrap(), d("40.00"));
+
+        let items = [item("mug", 1, "1.00")];
+        assert!(matches!(off(&disc, &items, "0"), Err(NotApplicable::ItemNotInCart { .. })));
+    }
+
+    #[test]
+    fn currency_must_match() {
+        let disc = discount(DiscountKind::FixedAmount, Some("5"));
+        let items = [item("a", 1, "10.00")];
+        assert!(matches!(
+            disc.amount_off("EUR", &items, d("10.00"), Decimal::ZERO),
+            Err(NotApplicable::CurrencyMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,175 @@
+use std::fmt;
+
+use async_graphql::ErrorExtensions;
+
+// ---------- API errors ----------
+
+/// Everything a resolver can fail with. `code()` goes out as the GraphQL error's
+/// `extensions.code`, so clients can branch on it instead of on the message.
+#[derive(Debug, Clone)]
+pub enum ApiError {
+    /// No or bad bearer token
+    Unauthenticated(String),
+    /// Wrong email or password
+    InvalidCredentials,
+    /// Signed in, but not allowed
+    Forbidden(String),
+    NotFound(String),
+    /// The caller already holds the coupon
+    AlreadyClaimed,
+    /// The caller doesn't hold the coupon
+    NotClaimed,
+    /// Every holder slot of the coupon is taken
+    FullyClaimed,
+    Expired,
+    NotYetValid,
+    UsageLimitReached(String),
+    /// Bad input
+    Validation(String),
+    /// Clashes with the current state, e.g. a taken code
+    Conflict(String),
+    /// Unknown, used or expired reset/verification/login token
+    InvalidToken(String),
+    /// Password accepted; finish with `verifyTwoFactor` and this token
+    MfaRequired { mfa_token: String },
+    /// Wrong authenticator or recovery code
+    InvalidCode,
+    /// Too many attempts; retry after this many seconds
+    RateLimited { retry_after: i64 },
+    /// Logged server-side; clients only see a generic message
+    Internal,
+    /// One of ours that already went through async-graphql (e.g. out of a pagination
+    /// closure) and carries its code; passed through as is
+    Graphql(async_graphql::Error),
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Unauthenticated(_) => "UNAUTHENTICATED",
+            ApiError::InvalidCredentials => "INVALID_CREDENTIALS",
+            ApiError::Forbidden(_) => "FORBIDDEN",
+            ApiError::NotFound(_) => "NOT_FOUND",
+            ApiError::AlreadyClaimed => "ALREADY_CLAIMED",
+            ApiError::NotClaimed => "NOT_CLAIMED",
+            ApiError::FullyClaimed => "FULLY_CLAIMED",
+            ApiError::Expired => "EXPIRED",
+            ApiError::NotYetValid => "NOT_YET_VALID",
+            ApiError::UsageLimitReached(_) => "USAGE_LIMIT_REACHED",
+            ApiError::Validation(_) => "VALIDATION",
+            ApiError::Conflict(_) => "CONFLICT",
+            ApiError::InvalidToken(_) => "INVALID_TOKEN",
+            ApiError::MfaRequired { .. } => "MFA_REQUIRED",
+            ApiError::InvalidCode => "INVALID_CODE",
+            ApiError::RateLimited { .. } => "RATE_LIMITED",
+            ApiError::Internal => "INTERNAL_SERVER_ERROR",
+            ApiError::Graphql(_) => "INTERNAL_SERVER_ERROR", // never used: the inner error has a code
+        }
+    }
+
+    pub fn unauthenticated(msg: impl Into<String>) -> Self {
+        ApiError::Unauthenticated(msg.into())
+    }
+
+    pub fn forbidden(msg: impl Into<String>) -> Self {
+        ApiError::Forbidden(msg.into())
+    }
+
+    pub fn not_found(msg: impl Into<String>) -> Self {
+        ApiError::NotFound(msg.into())
+    }
+
+    pub fn validation(msg: impl Into<String>) -> Self {
+        ApiError::Validation(msg.into())
+    }
+
+    pub fn conflict(msg: impl Into<String>) -> Self {
+        ApiError::Conflict(msg.into())
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Unauthenticated(m)
+            | ApiError::Forbidden(m)
+            | ApiError::NotFound(m)
+            | ApiError::UsageLimitReached(m)
+            | ApiError::Validation(m)
+            | ApiError::Conflict(m)
+            | ApiError::InvalidToken(m) => f.write_str(m),
+            ApiError::InvalidCredentials => f.write_str("Invalid email or password"),
+            ApiError::MfaRequired { .. } => f.write_str("Enter the code from your authenticator app"),
+            ApiError::InvalidCode => f.write_str("Invalid authentication code"),
+            ApiError::AlreadyClaimed => f.write_str("You already hold this coupon"),
+            ApiError::NotClaimed => f.write_str("Claim this coupon before using it"),
+            ApiError::FullyClaimed => f.write_str("This coupon has no free holder slots left"),
+            ApiError::Expired => f.write_str("This coupon has expired"),
+            ApiError::NotYetValid => f.write_str("This coupon isn't valid yet"),
+            ApiError::RateLimited { retry_after } => {
+                write!(f, "Too many failed login attempts; try again in {retry_after} seconds")
+            }
+            ApiError::Internal => f.write_str("Internal server error"),
+            ApiError::Graphql(e) => f.write_str(&e.message),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+// Domain code bails with an ApiError when the client should see why; anything else
+// (database, IO, ...) is logged here and reported as INTERNAL_SERVER_ERROR.
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<ApiError>() {
+            Ok(api) => api,
+            Err(e) => {
+                tracing::error!("internal error: {e:#}");
+                ApiError::Internal
+            }
+        }
+    }
+}
+
+// `connection::query` hands back our own errors from the page closure, already coded,
+// and its own for bad paging arguments (malformed cursor, negative first/last).
+impl From<async_graphql::Error> for ApiError {
+    fn from(e: async_graphql::Error) -> Self {
+        if e.extensions.as_ref().is_some_and(|ext| ext.get("code").is_some()) {
+            ApiError::Graphql(e)
+        } else {
+            ApiError::Validation(e.message)
+        }
+    }
+}
+
+impl From<ApiError> for async_graphql::Error {
+    fn from(e: ApiError) -> Self {
+        let code = e.code();
+        let retry_after = match e {
+            ApiError::RateLimited { retry_after } => Some(retry_after),
+            _ => None,
+        };
+        let mfa_token = match &e {
+            ApiError::MfaRequired { mfa_token } => Some(mfa_token.clone()),
+            _ => None,
+        };
+        let base = match e {
+            ApiError::Graphql(inner) => inner,
+            e => async_graphql::Error::new(e.to_string()),
+        };
+        base.extend_with(|_, ext| {
+            if ext.get("code").is_none() {
+                ext.set("code", code);
+            }
+            if let Some(secs) = retry_after {
+                ext.set("retry_after", secs);
+            }
+            if let Some(token) = &mfa_token {
+                ext.set("mfa_token", token.as_str());
+            }
+        })
+    }
+}
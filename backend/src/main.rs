@@ -1,24 +1,30 @@
 mod auth;
+mod authz;
 mod schema;
 mod db;
+mod discount;
+mod error;
+mod codes;
+mod coupon_csv;
+mod mail;
 
 use axum::{
     routing::{get, post},
     Router,
-    extract::State,
-    response::{IntoResponse, Html},
-    http::{StatusCode, HeaderMap},
+    extract::{ConnectInfo, State, WebSocketUpgrade},
+    response::{IntoResponse, Html, Response},
+    http::{StatusCode, HeaderMap, HeaderValue},
 };
-use async_graphql::{Schema, EmptySubscription};
-use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
-use async_graphql::http::GraphiQLSource;
+use async_graphql::{Schema, Data};
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse, GraphQLProtocol, GraphQLWebSocket};
+use async_graphql::http::{GraphiQLSource, ALL_WEBSOCKET_PROTOCOLS};
 use tower_http::{
     cors::{CorsLayer, Any},
     services::ServeDir,
 };
 use tracing_subscriber::EnvFilter;
 
-use schema::{AppSchema, QueryRoot, MutationRoot, AppState};
+use schema::{AppSchema, QueryRoot, MutationRoot, SubscriptionRoot, AppState, CouponEventKind};
 
 #[derive(Clone)]
 struct AppCtx {
@@ -39,13 +45,46 @@ async fn main() -> anyhow::Result<()> {
 
     let pool = db::pool(&database_url).await?;
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => {}
+        [cmd, email] if cmd == "grant-superadmin" => return grant_superadmin(&pool, email).await,
+        _ => anyhow::bail!("usage: coupon-auth [grant-superadmin <email>]"),
+    }
+
+    let mailer = mail::from_env()?;
+    let public_url =
+        std::env::var("PUBLIC_URL").unwrap_or_else(|_| "http://localhost:3000".into());
+    // On unless REQUIRE_EMAIL_VERIFICATION=false
+    let require_email_verification =
+        std::env::var("REQUIRE_EMAIL_VERIFICATION").map_or(true, |v| v != "false" && v != "0");
+    let password_policy = auth::PasswordPolicy::from_env();
+    // Dev only: DEV_FIRST_USER_ADMIN=true makes the first user to register superadmin
+    let first_user_admin =
+        std::env::var("DEV_FIRST_USER_ADMIN").is_ok_and(|v| v == "true" || v == "1");
+    let setup_token = setup_token(&pool).await?;
+
+    // Denylist rows only matter until the token would have expired anyway
+    tokio::spawn(prune_revoked_tokens(pool.clone()));
+
+    let (events, _) = tokio::sync::broadcast::channel(1024);
+
     let state = schema::AppState {
         pool: pool.clone(),
         jwt_secret,
+        events,
+        mailer,
+        public_url,
+        require_email_verification,
+        password_policy,
+        setup_token: std::sync::Arc::new(tokio::sync::Mutex::new(setup_token)),
+        first_user_admin,
     };
 
+    tokio::spawn(watch_coupon_expiry(state.clone()));
+
     let schema: AppSchema =
-        Schema::build(QueryRoot, MutationRoot, EmptySubscription)
+        Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
             .data(state.clone())
             .finish();
 
@@ -56,8 +95,13 @@ async fn main() -> anyhow::Result<()> {
     let app = Router::new()
         // GraphQL API + GraphiQL UI
         .route("/graphql", post(graphql_handler).get(graphiql))
+        // GraphQL subscriptions (graphql-ws / graphql-transport-ws)
+        .route("/ws", get(graphql_ws_handler))
         // Locked REST endpoint (JWT required)
         .route("/secret", get(secret_handler))
+        // CSV export (coupon:read; listCoupons filters as query params) and import (coupon:create; ?commit=true to save)
+        .route("/admin/coupons/export", get(coupon_csv::export_coupons))
+        .route("/admin/coupons/import", post(coupon_csv::import_coupons))
         // Serve static site at /
         .fallback_service(static_files)
         // CORS
@@ -67,24 +111,74 @@ async fn main() -> anyhow::Result<()> {
     let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 3000));
     println!("GraphiQL → http://localhost:3000/graphiql");
     println!("Demo page → http://localhost:3000/");
-    axum::serve(tokio::net::TcpListener::bind(addr).await?, app).await?;
+    axum::serve(
+        tokio::net::TcpListener::bind(addr).await?,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await?;
     Ok(())
 }
 
-// Inject the incoming request headers into the GraphQL context
+// Inject the incoming request headers and client address into the GraphQL context
 async fn graphql_handler(
     State(ctx): State<AppCtx>,
+    ConnectInfo(addr): ConnectInfo<std::net::SocketAddr>,
     headers: HeaderMap,        // <-- non-body extractor(s) first
     req: GraphQLRequest,       // <-- body extractor LAST
 ) -> GraphQLResponse {
     ctx.schema
-        .execute(req.into_inner().data(headers)) // inject headers into GQL context
+        .execute(req.into_inner().data(headers).data(addr)) // inject into GQL context
         .await
         .into()
 }
 
 async fn graphiql() -> impl IntoResponse {
-    Html(GraphiQLSource::build().endpoint("/graphql").finish())
+    Html(GraphiQLSource::build().endpoint("/graphql").subscription_endpoint("/ws").finish())
+}
+
+async fn graphql_ws_handler(
+    State(ctx): State<AppCtx>,
+    ConnectInfo(addr): ConnectInfo<std::net::SocketAddr>,
+    protocol: GraphQLProtocol,
+    websocket: WebSocketUpgrade,
+) -> Response {
+    websocket
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |stream| {
+            GraphQLWebSocket::new(stream, ctx.schema.clone(), protocol)
+                .on_connection_init(move |payload| ws_connection_init(payload, addr))
+                .serve()
+        })
+}
+
+// Browsers can't set headers on a WebSocket, so the bearer token (or API key) comes
+// in the connection_init payload ({"Authorization": "Bearer ..."} or {"X-Api-Key": "..."})
+// and is exposed to resolvers as a HeaderMap, just like on POST /graphql.
+async fn ws_connection_init(payload: serde_json::Value, addr: std::net::SocketAddr) -> async_graphql::Result<Data> {
+    let mut data = Data::default();
+    data.insert(addr);
+    let field = |name: &str| {
+        payload.get(name)
+            .or_else(|| payload.get(name.to_lowercase()))
+            .and_then(|v| v.as_str())
+    };
+    let mut headers = HeaderMap::new();
+    if let Some(authz) = field("Authorization") {
+        headers.insert(
+            axum::http::header::AUTHORIZATION,
+            HeaderValue::from_str(authz).map_err(|_| "Invalid Authorization value")?,
+        );
+    }
+    if let Some(key) = field("X-Api-Key") {
+        headers.insert(
+            crate::auth::API_KEY_HEADER,
+            HeaderValue::from_str(key).map_err(|_| "Invalid X-Api-Key value")?,
+        );
+    }
+    if !headers.is_empty() {
+        data.insert(headers);
+    }
+    Ok(data)
 }
 
 async fn secret_handler(
@@ -98,7 +192,7 @@ async fn secret_handler(
     let Some(token) = authz.strip_prefix("Bearer ") else {
         return (StatusCode::UNAUTHORIZED, "Invalid Authorization header".to_string());
     };
-    match crate::auth::parse_jwt(&ctx.state.jwt_secret, token) {
+    match crate::auth::parse_jwt(&ctx.state.pool, &ctx.state.jwt_secret, token).await {
         Ok(user_id) => {
             let msg = format!(r#"{{"message":"Welcome, user {}. This is the locked page."}}"#, user_id);
             (StatusCode::OK, msg)
@@ -106,3 +200,79 @@ async fn secret_handler(
         Err(_) => (StatusCode::UNAUTHORIZED, "Invalid or expired token".to_string()),
     }
 }
+
+// While nobody is superadmin, `bootstrapAdmin` takes a one-time token: SETUP_TOKEN, or a random one
+// printed here.
+async fn setup_token(pool: &sqlx::SqlitePool) -> anyhow::Result<Option<String>> {
+    if db::superadmin_exists(pool).await? {
+        return Ok(None);
+    }
+    let token = match std::env::var("SETUP_TOKEN") {
+        Ok(t) if t.len() < 16 => anyhow::bail!("SETUP_TOKEN must be at least 16 characters"),
+        Ok(t) => t,
+        Err(_) => {
+            let t = auth::make_opaque_token();
+            println!("No superadmin yet. One-time setup token for bootstrapAdmin: {t}");
+            t
+        }
+    };
+    Ok(Some(token))
+}
+
+// `coupon-auth grant-superadmin <email>`: promote an existing account from the server's shell.
+async fn grant_superadmin(pool: &sqlx::SqlitePool, email: &str) -> anyhow::Result<()> {
+    let Some(u) = db::find_user_by_email(pool, &auth::canonical_email(email)).await? else {
+        anyhow::bail!("no account with email {email}");
+    };
+    let actor = db::Actor::default();
+    if db::assign_role(pool, &u.id, authz::SUPERADMIN, &actor).await? {
+        println!("{} is now {}", u.email, authz::SUPERADMIN);
+    } else {
+        println!("{} already is {}", u.email, authz::SUPERADMIN);
+    }
+    Ok(())
+}
+
+async fn prune_revoked_tokens(pool: sqlx::SqlitePool) {
+    let mut tick = tokio::time::interval(std::time::Duration::from_secs(10 * 60));
+    loop {
+        tick.tick().await;
+        match db::prune_revoked_tokens(&pool).await {
+            Ok(n) if n > 0 => tracing::info!("pruned {n} expired revoked tokens"),
+            Ok(_) => {}
+            Err(e) => tracing::warn!("pruning revoked tokens failed: {e}"),
+        }
+        if let Err(e) = db::prune_password_resets(&pool).await {
+            tracing::warn!("pruning password resets failed: {e}");
+        }
+        if let Err(e) = db::prune_email_verifications(&pool).await {
+            tracing::warn!("pruning email verifications failed: {e}");
+        }
+        if let Err(e) = db::prune_login_failures(&pool).await {
+            tracing::warn!("pruning login failures failed: {e}");
+        }
+        if let Err(e) = db::prune_mfa_challenges(&pool).await {
+            tracing::warn!("pruning two-factor challenges failed: {e}");
+        }
+    }
+}
+
+// Announce coupons as they pass their expiry time.
+async fn watch_coupon_expiry(state: AppState) {
+    let mut last = chrono::Utc::now().timestamp();
+    let mut tick = tokio::time::interval(std::time::Duration::from_secs(30));
+    loop {
+        tick.tick().await;
+        let now = chrono::Utc::now().timestamp();
+        match db::coupons_expired_between(&state.pool, last, now).await {
+            Ok(expired) => {
+                for c in expired {
+                    let user_ids = db::coupon_holders(&state.pool, &c.id).await.unwrap_or_default();
+                    state.publish(CouponEventKind::Expired, c, user_ids);
+                }
+                last = now;
+            }
+            Err(e) => tracing::warn!("checking for expired coupons failed: {e}"),
+        }
+    }
+}
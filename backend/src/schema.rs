@@ -1,15 +1,63 @@
 use async_graphql::{
-    Context, Object, Schema, EmptySubscription, Result as GqlResult, SimpleObject, InputObject,
+    Context, Object, Subscription, Schema, SimpleObject, InputObject, Enum, Json,
+    connection::{self, Connection, CursorType, Edge},
 };
-use sqlx::{Row, SqlitePool};
+use std::net::SocketAddr;
+use std::sync::Arc;
 
-use crate::{auth, db};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::Decimal;
+use sqlx::SqlitePool;
+use tokio::sync::broadcast;
+use tokio_stream::Stream;
+
+use crate::authz::{Permission, Principal};
+use crate::error::{ApiError, ApiResult};
+use crate::{auth, authz, codes, db, discount, mail};
 
 // ---------- App State ----------
 #[derive(Clone)]
 pub struct AppState {
     pub pool: SqlitePool,
     pub jwt_secret: String,
+    /// Coupon lifecycle events fanned out to GraphQL subscribers
+    pub events: broadcast::Sender<CouponEvent>,
+    pub mailer: Arc<dyn mail::Mailer>,
+    /// Base URL for links in emails, e.g. `https://coupons.example.com`
+    pub public_url: String,
+    /// Claiming and redeeming coupons need a verified email address
+    pub require_email_verification: bool,
+    /// Enforced on register and resetPassword
+    pub password_policy: auth::PasswordPolicy,
+    /// One-time token for `bootstrapAdmin`; set at startup while there's no superadmin, cleared once used
+    pub setup_token: Arc<tokio::sync::Mutex<Option<String>>>,
+    /// Dev mode: the first user to register becomes superadmin
+    pub first_user_admin: bool,
+}
+
+impl AppState {
+    /// Fire-and-forget; having no subscribers is not an error.
+    pub fn publish(&self, kind: CouponEventKind, coupon: db::DbCoupon, user_ids: Vec<String>) {
+        let _ = self.events.send(CouponEvent {
+            kind,
+            coupon: Some(db_coupon_to_gql(coupon)),
+            batch: None,
+            at: chrono::Utc::now().timestamp(),
+            user_ids,
+        });
+    }
+
+    /// One event for a whole generated batch, however many codes it holds.
+    pub fn publish_batch(&self, batch: db::DbCouponBatch) {
+        let _ = self.events.send(CouponEvent {
+            kind: CouponEventKind::BatchCreated,
+            coupon: None,
+            batch: Some(db_batch_to_gql(batch)),
+            at: chrono::Utc::now().timestamp(),
+            user_ids: Vec::new(),
+        });
+    }
 }
 
 // ---------- GraphQL Types ----------
@@ -18,7 +66,28 @@ pub struct AppState {
 pub struct User {
     pub id: String,
     pub email: String,
-    pub is_admin: bool,
+    /// e.g. `superadmin`, `campaign_manager`, `support`, `auditor`
+    pub roles: Vec<String>,
+    /// Everything the roles grant, e.g. `coupon:create`
+    pub permissions: Vec<String>,
+    pub created_at: i64,           // unix seconds
+    /// Set while the account is suspended (no login, tokens refused)
+    pub suspended_at: Option<i64>,
+    pub suspended_reason: Option<String>,
+    /// null until the user follows the link from the verification email
+    pub email_verified_at: Option<i64>,
+    /// Set while logins are locked after repeated failed passwords
+    pub login_locked_until: Option<i64>,
+    /// Set once two-factor authentication is confirmed
+    pub totp_enabled_at: Option<i64>,
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(rename_fields = "snake_case")]
+pub struct Role {
+    pub name: String,
+    pub description: String,
+    pub permissions: Vec<String>,
 }
 
 #[derive(SimpleObject, Clone)]
@@ -28,11 +97,286 @@ pub struct Coupon {
     pub code: String,
     pub description: String,
     pub service: String,
-    pub expires_at: i64,           // unix seconds
-    pub owner_id: Option<String>,  // nullable
+    pub valid_from: i64,           // unix seconds; claimable and redeemable from here ...
+    pub expires_at: i64,           // ... until here
+    /// Holder of a single-holder coupon (`max_claims` 1); `null` if unclaimed or shareable
+    pub owner_id: Option<String>,
+    pub created_at: i64,
+    /// Machine-readable discount terms (`null` for description-only coupons)
+    pub discount: Option<Discount>,
+    /// Set when the coupon came from `generateCoupons`
+    pub batch_id: Option<String>,
+    /// Total redemptions allowed across all users (`null` = unlimited)
+    pub max_redemptions: Option<i64>,
+    /// Redemptions allowed per user (`null` = unlimited)
+    pub max_redemptions_per_user: Option<i64>,
+    pub redemption_count: i64,
+    /// Uses left across all users (`null` = unlimited)
+    pub remaining_uses: Option<i64>,
+    /// How many users can hold the coupon at once (`null` = unlimited)
+    pub max_claims: Option<i64>,
+    /// How many users hold it now
+    pub claim_count: i64,
+    /// When an admin archived it (only seen through `archivedCoupons`)
+    pub deleted_at: Option<i64>,
+    pub deleted_by: Option<String>,
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(rename_fields = "snake_case")]
+pub struct CouponBatch {
+    pub id: String,
+    pub pattern: String,
+    pub count: i64,
+    pub description: String,
+    pub service: String,
+    pub valid_from: i64,           // unix seconds
+    pub expires_at: i64,
+    pub created_by: Option<String>,
     pub created_at: i64,
 }
 
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum DiscountKind {
+    Percentage,
+    FixedAmount,
+    FreeShipping,
+    FreeItem,
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(rename_fields = "snake_case")]
+pub struct Discount {
+    pub kind: DiscountKind,
+    /// Percent off, amount off, or free-item quantity depending on `kind`
+    pub value: Option<Decimal>,
+    pub currency: Option<String>,
+    pub sku: Option<String>,
+    pub min_order_amount: Option<Decimal>,
+    pub max_discount_amount: Option<Decimal>,
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(rename_fields = "snake_case")]
+pub struct Redemption {
+    pub id: i64,
+    pub coupon_id: String,
+    pub user_id: String,
+    pub redeemed_at: i64,          // unix seconds
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum CouponRejectionReason {
+    NotFound,
+    NotOwned,
+    /// The validity window has not started yet
+    NotYetValid,
+    Expired,
+    /// The caller has used up their per-user allowance
+    AlreadyRedeemed,
+    /// The coupon has no uses left for anyone
+    UsageLimitReached,
+    /// Coupon only has a free-text description
+    NoDiscountTerms,
+    CurrencyMismatch,
+    MinimumNotMet,
+    ItemNotInCart,
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(rename_fields = "snake_case")]
+pub struct CouponRejection {
+    pub reason: CouponRejectionReason,
+    pub message: String,
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(rename_fields = "snake_case")]
+pub struct OrderQuote {
+    pub currency: String,
+    pub subtotal: Decimal,
+    pub shipping: Decimal,
+    /// Amount taken off by the coupon (0 if none applied)
+    pub discount: Decimal,
+    pub total: Decimal,
+    /// Code of the coupon that was applied
+    pub applied_coupon: Option<String>,
+    /// Why the requested coupon was not applied
+    pub rejection: Option<CouponRejection>,
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(rename_fields = "snake_case")]
+pub struct AuthPayload {
+    /// Short-lived (3 minute) bearer JWT
+    pub access_token: String,
+    /// Long-lived opaque token; exchange via `refreshToken` (single use, rotates)
+    pub refresh_token: String,
+}
+
+#[derive(SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct TotpEnrollment {
+    /// Base32; for typing into an authenticator app by hand
+    pub secret: String,
+    /// `otpauth://` URI, usually shown as a QR code
+    pub otpauth_uri: String,
+}
+
+#[derive(SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct RegisterPayload {
+    /// The new account; null while email verification is required, so taken
+    /// addresses get the same answer as free ones
+    pub user: Option<User>,
+    /// Check your email: the account has to be confirmed through the link sent there
+    pub verification_required: bool,
+}
+
+#[derive(SimpleObject)]
+pub struct CouponConnectionFields {
+    /// Number of coupons matching the query, across all pages
+    pub total_count: i64,
+}
+
+pub type CouponConnection = Connection<db::CouponCursor, Coupon, CouponConnectionFields>;
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+const MAX_BATCH_SIZE: usize = 10_000;
+
+// Opaque to clients: base64("<sort column>:<id>:<i|s><value>")
+impl CursorType for db::CouponCursor {
+    type Error = String;
+
+    fn decode_cursor(s: &str) -> Result<Self, Self::Error> {
+        let invalid = || "invalid cursor".to_string();
+        let raw = URL_SAFE_NO_PAD.decode(s).map_err(|_| invalid())?;
+        let raw = String::from_utf8(raw).map_err(|_| invalid())?;
+        let mut parts = raw.splitn(3, ':');
+        let (Some(col), Some(id), Some(value)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let sort = db::CouponSortKey::from_column(col).ok_or_else(invalid)?;
+        let value = match value.split_at_checked(1) {
+            Some(("i", v)) => db::SortValue::Int(v.parse().map_err(|_| invalid())?),
+            Some(("s", v)) => db::SortValue::Text(v.to_string()),
+            _ => return Err(invalid()),
+        };
+        Ok(db::CouponCursor { sort, value, id: id.to_string() })
+    }
+
+    fn encode_cursor(&self) -> String {
+        let value = match &self.value {
+            db::SortValue::Int(v) => format!("i{v}"),
+            db::SortValue::Text(v) => format!("s{v}"),
+        };
+        URL_SAFE_NO_PAD.encode(format!("{}:{}:{}", self.sort.column(), self.id, value))
+    }
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum CouponEventKind {
+    Created,
+    Updated,
+    Deleted,
+    Claimed,
+    Released,
+    Redeemed,
+    Expired,
+    Restored,
+    BatchCreated,
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(rename_fields = "snake_case")]
+pub struct CouponEvent {
+    pub kind: CouponEventKind,
+    /// Coupon state after the change (last known state for `DELETED`); null for `BATCH_CREATED`
+    pub coupon: Option<Coupon>,
+    /// The generated batch, for `BATCH_CREATED`; its coupons don't get events of their own
+    pub batch: Option<CouponBatch>,
+    pub at: i64,                   // unix seconds
+    /// Users whose coupons this concerns (holders before and/or after); drives `myCouponEvents`
+    #[graphql(skip)]
+    pub user_ids: Vec<String>,
+}
+
+impl CouponEvent {
+    fn service(&self) -> &str {
+        match (&self.coupon, &self.batch) {
+            (Some(c), _) => &c.service,
+            (None, Some(b)) => &b.service,
+            (None, None) => "",
+        }
+    }
+}
+
+#[derive(SimpleObject, Clone)]
+#[graphql(rename_fields = "snake_case")]
+pub struct AuditEvent {
+    pub id: i64,
+    /// User who did it; `null` for anonymous actions such as registration, and for API keys
+    pub actor_id: Option<String>,
+    /// Set when a merchant API key did it
+    pub api_key_id: Option<String>,
+    /// e.g. `coupon.create`, `coupon.claim`, `user.register`
+    pub action: String,
+    /// `coupon`, `coupon_batch` or `user`
+    pub target_type: String,
+    pub target_id: String,
+    /// Target state before the change (`null` when it didn't exist)
+    pub before: Option<Json<serde_json::Value>>,
+    /// Target state after the change (`null` when it no longer exists)
+    pub after: Option<Json<serde_json::Value>>,
+    /// Client address
+    pub ip: Option<String>,
+    pub created_at: i64,           // unix seconds
+}
+
+#[derive(SimpleObject)]
+pub struct AuditConnectionFields {
+    /// Number of events matching the filter, across all pages
+    pub total_count: i64,
+}
+
+pub type AuditConnection = Connection<i64, AuditEvent, AuditConnectionFields>;
+
+#[derive(SimpleObject)]
+pub struct UserConnectionFields {
+    /// Number of users matching the filter, across all pages
+    pub total_count: i64,
+}
+
+// Cursors are emails; the listing is ordered by email.
+pub type UserConnection = Connection<String, User, UserConnectionFields>;
+
+#[derive(SimpleObject, Clone)]
+#[graphql(rename_fields = "snake_case")]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    /// First characters of the key, to tell keys apart; the key itself isn't stored
+    pub key_prefix: String,
+    /// Coupons of these services are all the key can reach
+    pub services: Vec<String>,
+    /// e.g. `coupon:read`, `coupon:create`
+    pub permissions: Vec<String>,
+    /// User who created it
+    pub created_by: Option<String>,
+    pub created_at: i64,           // unix seconds
+    pub last_used_at: Option<i64>, // unix seconds, to the minute
+    pub revoked_at: Option<i64>,
+}
+
+#[derive(SimpleObject)]
+#[graphql(rename_fields = "snake_case")]
+pub struct NewApiKey {
+    /// Send as the `X-Api-Key` header. Shown only this once.
+    pub key: String,
+    pub api_key: ApiKey,
+}
+
 // ---------- Inputs ----------
 #[derive(InputObject)]
 pub struct RegisterInput { pub email: String, pub password: String }
@@ -40,15 +384,137 @@ pub struct RegisterInput { pub email: String, pub password: String }
 #[derive(InputObject)]
 pub struct LoginInput { pub email: String, pub password: String }
 
+#[derive(InputObject)]
+pub struct CreateApiKeyInput {
+    /// What the key is for, e.g. "my-store checkout"
+    pub name: String,
+    /// At least one
+    pub services: Vec<String>,
+    /// Any of `coupon:read`, `coupon:create`, `coupon:update`, `coupon:delete`, `coupon:purge`
+    pub permissions: Vec<String>,
+}
+
+#[derive(InputObject)]
+pub struct DiscountInput {
+    pub kind: DiscountKind,
+    /// PERCENTAGE: percent off (0, 100]. FIXED_AMOUNT: amount off. FREE_ITEM: quantity (default 1).
+    pub value: Option<Decimal>,
+    /// ISO 4217; required for FIXED_AMOUNT and whenever an order limit is set
+    pub currency: Option<String>,
+    /// FREE_ITEM only
+    pub sku: Option<String>,
+    pub min_order_amount: Option<Decimal>,
+    pub max_discount_amount: Option<Decimal>,
+}
+
 #[derive(InputObject)]
 pub struct CreateCouponInput {
     pub code: String,
     pub description: String,
     pub service: String,
+    /// RFC 3339 start of the validity window; defaults to now
+    pub valid_from: Option<DateTime<Utc>>,
+    /// RFC 3339 expiry instant (give this or `expiresInDays`)
+    pub expires_at: Option<DateTime<Utc>>,
     /// How many days from now it should expire
-    pub expires_in_days: i64,
+    pub expires_in_days: Option<i64>,
     /// Optional: assign to a user id at creation
     pub owner_id: Option<String>,
+    pub discount: Option<DiscountInput>,
+    /// Total redemptions allowed; defaults to 1, `null` = unlimited
+    #[graphql(default_with = "Some(1)")]
+    pub max_redemptions: Option<i32>,
+    /// Redemptions allowed per user; defaults to 1, `null` = unlimited
+    #[graphql(default_with = "Some(1)")]
+    pub max_redemptions_per_user: Option<i32>,
+    /// How many users can claim the code at once; defaults to 1, `null` = unlimited (shareable code)
+    #[graphql(default_with = "Some(1)")]
+    pub max_claims: Option<i32>,
+}
+
+#[derive(InputObject, Default)]
+pub struct CouponFilter {
+    /// Exact service match
+    pub service: Option<String>,
+    /// Any of these services (combined with `service` if both are given)
+    pub services: Option<Vec<String>>,
+    pub code_prefix: Option<String>,
+    /// Case-insensitive substring of the description
+    pub description_contains: Option<String>,
+    /// Unix seconds, exclusive
+    pub expires_after: Option<i64>,
+    pub expires_before: Option<i64>,
+    pub created_after: Option<i64>,
+    pub created_before: Option<i64>,
+    /// true = claimed by someone, false = unclaimed
+    pub owned: Option<bool>,
+    /// Coupons held by this user id (needs `coupon:read`)
+    pub owner_id: Option<String>,
+    /// Coupons created by one `generateCoupons` call
+    pub batch_id: Option<String>,
+}
+
+#[derive(InputObject)]
+pub struct GenerateCouponsInput {
+    /// How many coupons to create (at most 10000)
+    pub count: i32,
+    /// e.g. `SUMMER-XXXX-XXXX`: each `X` is a random character, `\X` a literal X
+    pub pattern: String,
+    /// Characters to draw from (defaults to A-Z and 2-9 without look-alikes 0/O, 1/I)
+    pub alphabet: Option<String>,
+    pub description: String,
+    pub service: String,
+    /// RFC 3339 start of the validity window; defaults to now
+    pub valid_from: Option<DateTime<Utc>>,
+    /// RFC 3339 expiry instant (give this or `expiresInDays`)
+    pub expires_at: Option<DateTime<Utc>>,
+    /// How many days from now they should expire
+    pub expires_in_days: Option<i64>,
+    pub discount: Option<DiscountInput>,
+}
+
+#[derive(InputObject, Default)]
+pub struct AuditFilter {
+    pub actor_id: Option<String>,
+    /// Exact action, e.g. `coupon.update`
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    /// Unix seconds, exclusive
+    pub created_after: Option<i64>,
+    pub created_before: Option<i64>,
+}
+
+#[derive(InputObject, Default)]
+pub struct UserFilter {
+    /// Case-insensitive substring of the email
+    pub email_contains: Option<String>,
+    /// Users holding this role
+    pub role: Option<String>,
+    /// true = suspended only, false = active only
+    pub suspended: Option<bool>,
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum CouponSortField {
+    CreatedAt,
+    ExpiresAt,
+    Code,
+    Service,
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum SortDirection {
+    Asc,
+    Desc,
+}
+
+#[derive(InputObject)]
+pub struct CouponSort {
+    #[graphql(default_with = "CouponSortField::CreatedAt")]
+    pub field: CouponSortField,
+    #[graphql(default_with = "SortDirection::Desc")]
+    pub direction: SortDirection,
 }
 
 #[derive(InputObject)]
@@ -58,15 +524,53 @@ pub struct UpdateCouponInput {
     /// Optional updates; omit to leave unchanged
     pub description: Option<String>,
     pub service: Option<String>,
+    /// RFC 3339 start of the validity window
+    pub valid_from: Option<DateTime<Utc>>,
+    /// RFC 3339 expiry instant (or `expiresInDays`, counted from now)
+    pub expires_at: Option<DateTime<Utc>>,
     pub expires_in_days: Option<i64>,
-    /// Set new owner (takes precedence if provided)
+    /// Make this user the only holder (takes precedence if provided)
     pub owner_id: Option<String>,
-    /// If true and owner_id not provided, clear owner
+    /// If true and owner_id not provided, remove every holder
     pub clear_owner: Option<bool>,
+    /// Replace the discount terms (takes precedence if provided)
+    pub discount: Option<DiscountInput>,
+    /// If true and discount not provided, remove the discount terms
+    pub clear_discount: Option<bool>,
+    /// New total redemption cap (takes precedence if provided)
+    pub max_redemptions: Option<i32>,
+    /// If true and max_redemptions not provided, allow unlimited redemptions
+    pub clear_max_redemptions: Option<bool>,
+    /// New per-user redemption cap (takes precedence if provided)
+    pub max_redemptions_per_user: Option<i32>,
+    /// If true and max_redemptions_per_user not provided, no per-user cap
+    pub clear_max_redemptions_per_user: Option<bool>,
+    /// New cap on simultaneous holders (takes precedence if provided)
+    pub max_claims: Option<i32>,
+    /// If true and max_claims not provided, anyone can claim
+    pub clear_max_claims: Option<bool>,
+}
+
+#[derive(InputObject)]
+pub struct LineItemInput {
+    pub sku: String,
+    pub quantity: i32,
+    pub unit_price: Decimal,
+    /// ISO 4217; all items in an order must share one currency
+    pub currency: String,
+}
+
+#[derive(InputObject)]
+pub struct QuoteOrderInput {
+    pub items: Vec<LineItemInput>,
+    /// Shipping cost in the order currency (defaults to 0)
+    pub shipping: Option<Decimal>,
+    /// Coupon to apply; must be held by the caller
+    pub coupon_code: Option<String>,
 }
 
 // ---------- Schema ----------
-pub type AppSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+pub type AppSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
 
 pub struct QueryRoot;
 
@@ -74,33 +578,279 @@ pub struct QueryRoot;
 impl QueryRoot {
     async fn health(&self) -> &str { "ok" }
 
-    async fn me(&self, ctx: &Context<'_>) -> GqlResult<Option<User>> {
-        let st = ctx.data_unchecked::<AppState>();
-        if let Some(uid) = user_id_from_headers(ctx, &st.jwt_secret)? {
-            let row = sqlx::query("SELECT id,email,is_admin FROM users WHERE id = ?")
-                .bind(&uid)
-                .fetch_optional(&st.pool)
-                .await?;
-            if let Some(r) = row {
-                return Ok(Some(User {
-                    id: r.get("id"),
-                    email: r.get("email"),
-                    is_admin: r.get::<i64,_>("is_admin") == 1,
-                }));
+    async fn me(&self, ctx: &Context<'_>) -> ApiResult<Option<User>> {
+        let st = ctx.data_unchecked::<AppState>();
+        if let Some(uid) = user_id_from_headers(ctx, &st.pool, &st.jwt_secret).await? {
+            if let Some(u) = db::find_user_by_id(&st.pool, &uid).await? {
+                return Ok(Some(gql_user(&st.pool, u).await?));
             }
         }
         Ok(None)
     }
 
-    /// Public list of coupons. `active_only` defaults to true.
+    /// Public list of coupons, newest first unless `sort` says otherwise.
+    /// `active_only` defaults to true.
+    #[allow(clippy::too_many_arguments)]
     async fn list_coupons(
         &self,
         ctx: &Context<'_>,
         #[graphql(default = true)] active_only: bool,
-    ) -> GqlResult<Vec<Coupon>> {
+        filter: Option<CouponFilter>,
+        sort: Option<CouponSort>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> ApiResult<CouponConnection> {
+        let st = ctx.data_unchecked::<AppState>();
+        let filter = filter.unwrap_or_default();
+        let staff = match filter.owner_id {
+            Some(_) => Some(require_staff(ctx, &st.pool, &st.jwt_secret, Permission::CouponRead).await?),
+            None => None,
+        };
+        let mut q = coupon_query(active_only, filter, sort);
+        if let Some(staff) = staff {
+            q.services = staff.scope_services(q.services)?;
+        }
+        coupon_connection(&st.pool, q, after, before, first, last).await
+    }
+
+    /// Price an order and apply one of the caller's coupons to it.
+    /// A coupon that doesn't apply is reported in `rejection`, not as an error.
+    async fn quote_order(&self, ctx: &Context<'_>, input: QuoteOrderInput) -> ApiResult<OrderQuote> {
+        let st = ctx.data_unchecked::<AppState>();
+        let uid = require_user(ctx, &st.pool, &st.jwt_secret).await?;
+
+        let Some(first) = input.items.first() else {
+            return Err(ApiError::validation("Invalid order: no line items"));
+        };
+        let currency = first.currency.trim().to_ascii_uppercase();
+        let mut items = Vec::with_capacity(input.items.len());
+        for i in &input.items {
+            if !i.currency.trim().eq_ignore_ascii_case(&currency) {
+                return Err(ApiError::validation("Invalid order: all line items must use the same currency"));
+            }
+            if i.quantity <= 0 || i.unit_price < Decimal::ZERO {
+                return Err(ApiError::validation(format!("Invalid order: bad quantity or price for {}", i.sku)));
+            }
+            items.push(discount::LineItem {
+                sku: i.sku.clone(),
+                quantity: i.quantity as u32,
+                unit_price: i.unit_price,
+            });
+        }
+        let shipping = input.shipping.unwrap_or(Decimal::ZERO);
+        if shipping < Decimal::ZERO {
+            return Err(ApiError::validation("Invalid order: shipping can't be negative"));
+        }
+        let subtotal = discount::subtotal(&items)
+            .filter(|s| *s + shipping <= discount::MAX_ORDER_AMOUNT)
+            .ok_or_else(|| ApiError::validation("Invalid order: amounts too large"))?;
+
+        let mut quote = OrderQuote {
+            currency: currency.clone(),
+            subtotal,
+            shipping,
+            discount: Decimal::ZERO,
+            total: subtotal + shipping,
+            applied_coupon: None,
+            rejection: None,
+        };
+        if let Some(code) = input.coupon_code {
+            match coupon_discount_for_order(&st.pool, &uid, &code, &currency, &items, subtotal, shipping).await? {
+                Ok(off) => {
+                    quote.discount = off;
+                    quote.total -= off;
+                    quote.applied_coupon = Some(code);
+                }
+                Err(rejection) => quote.rejection = Some(rejection),
+            }
+        }
+        Ok(quote)
+    }
+
+    /// Needs `coupon:read`. A coupon batch created by `generateCoupons`; list its codes with
+    /// `listCoupons(filter: { batchId })`.
+    async fn coupon_batch(&self, ctx: &Context<'_>, id: String) -> ApiResult<Option<CouponBatch>> {
         let st = ctx.data_unchecked::<AppState>();
-        let rows = db::list_coupons(&st.pool, active_only).await?;
-        Ok(rows.into_iter().map(db_coupon_to_gql).collect())
+        let staff = require_staff(ctx, &st.pool, &st.jwt_secret, Permission::CouponRead).await?;
+        let batch = db::get_coupon_batch(&st.pool, &id).await?;
+        if let Some(b) = &batch {
+            staff.check_service(&b.service)?;
+        }
+        Ok(batch.map(db_batch_to_gql))
+    }
+
+    /// Needs `coupon:read`. Archived (soft-deleted) coupons, newest first unless `sort` says otherwise.
+    #[allow(clippy::too_many_arguments)]
+    async fn archived_coupons(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<CouponFilter>,
+        sort: Option<CouponSort>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> ApiResult<CouponConnection> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff = require_staff(ctx, &st.pool, &st.jwt_secret, Permission::CouponRead).await?;
+        let mut q = coupon_query(false, filter.unwrap_or_default(), sort);
+        q.archived = true;
+        q.services = staff.scope_services(q.services)?;
+        coupon_connection(&st.pool, q, after, before, first, last).await
+    }
+
+    /// Needs `audit:read`. The audit log, newest first.
+    async fn audit_log(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<AuditFilter>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> ApiResult<AuditConnection> {
+        let st = ctx.data_unchecked::<AppState>();
+        require_permission(ctx, &st.pool, &st.jwt_secret, Permission::AuditRead).await?;
+        let f = filter.unwrap_or_default();
+        let q = db::AuditQuery {
+            actor_id: f.actor_id,
+            action: f.action,
+            target_type: f.target_type,
+            target_id: f.target_id,
+            created_after: f.created_after,
+            created_before: f.created_before,
+        };
+        let pool = &st.pool;
+        Ok(connection::query(after, before, first, last, |after, before, first, last| async move {
+            let from_end = last.is_some() && first.is_none();
+            let limit = first.or(last).unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE) as i64;
+
+            let (rows, has_more) = db::list_audit_events(pool, &q, after, before, limit, from_end).await?;
+            let total_count = db::count_audit_events(pool, &q).await?;
+
+            let (has_previous_page, has_next_page) = if from_end {
+                (has_more, before.is_some())
+            } else {
+                (after.is_some(), has_more)
+            };
+            let mut conn = Connection::with_additional_fields(
+                has_previous_page,
+                has_next_page,
+                AuditConnectionFields { total_count },
+            );
+            conn.edges.extend(rows.into_iter().map(|e| {
+                Edge::new(e.id, AuditEvent {
+                    id: e.id,
+                    actor_id: e.actor_id,
+                    api_key_id: e.api_key_id,
+                    action: e.action,
+                    target_type: e.target_type,
+                    target_id: e.target_id,
+                    before: e.before.map(Json),
+                    after: e.after.map(Json),
+                    ip: e.ip,
+                    created_at: e.created_at,
+                })
+            }));
+            Ok::<_, ApiError>(conn)
+        })
+        .await?)
+    }
+
+    /// Needs `user:manage`. Merchant API keys, newest first.
+    async fn api_keys(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = false)] include_revoked: bool,
+    ) -> ApiResult<Vec<ApiKey>> {
+        let st = ctx.data_unchecked::<AppState>();
+        require_permission(ctx, &st.pool, &st.jwt_secret, Permission::UserManage).await?;
+        Ok(db::list_api_keys(&st.pool, include_revoked).await?.into_iter().map(db_api_key_to_gql).collect())
+    }
+
+    /// Needs `user:manage`. Every role and the permissions it grants.
+    async fn roles(&self, ctx: &Context<'_>) -> ApiResult<Vec<Role>> {
+        let st = ctx.data_unchecked::<AppState>();
+        require_permission(ctx, &st.pool, &st.jwt_secret, Permission::UserManage).await?;
+        Ok(db::list_roles(&st.pool)
+            .await?
+            .into_iter()
+            .map(|r| Role { name: r.name, description: r.description, permissions: r.permissions })
+            .collect())
+    }
+
+    /// Needs `user:manage`. Accounts ordered by email.
+    async fn users(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<UserFilter>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> ApiResult<UserConnection> {
+        let st = ctx.data_unchecked::<AppState>();
+        require_permission(ctx, &st.pool, &st.jwt_secret, Permission::UserManage).await?;
+        let f = filter.unwrap_or_default();
+        let q = db::UserQuery { email_contains: f.email_contains, role: f.role, suspended: f.suspended };
+        let pool = &st.pool;
+        Ok(connection::query(after, before, first, last, |after: Option<String>, before: Option<String>, first, last| async move {
+            let from_end = last.is_some() && first.is_none();
+            let limit = first.or(last).unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE) as i64;
+
+            let (rows, has_more) =
+                db::list_users(pool, &q, after.as_deref(), before.as_deref(), limit, from_end).await?;
+            let total_count = db::count_users(pool, &q).await?;
+
+            let (has_previous_page, has_next_page) = if from_end {
+                (has_more, before.is_some())
+            } else {
+                (after.is_some(), has_more)
+            };
+            let mut conn = Connection::with_additional_fields(
+                has_previous_page,
+                has_next_page,
+                UserConnectionFields { total_count },
+            );
+            for u in rows {
+                conn.edges.push(Edge::new(u.email.clone(), gql_user(pool, u).await?));
+            }
+            Ok::<_, ApiError>(conn)
+        })
+        .await?)
+    }
+
+    /// Needs `user:manage`.
+    async fn user(&self, ctx: &Context<'_>, id: String) -> ApiResult<Option<User>> {
+        let st = ctx.data_unchecked::<AppState>();
+        require_permission(ctx, &st.pool, &st.jwt_secret, Permission::UserManage).await?;
+        match db::find_user_by_id(&st.pool, &id).await? {
+            Some(u) => Ok(Some(gql_user(&st.pool, u).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Needs `coupon:read`. Coupons a user holds, including expired ones unless `active_only`.
+    #[allow(clippy::too_many_arguments)]
+    async fn user_coupons(
+        &self,
+        ctx: &Context<'_>,
+        user_id: String,
+        #[graphql(default = false)] active_only: bool,
+        sort: Option<CouponSort>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> ApiResult<CouponConnection> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff = require_staff(ctx, &st.pool, &st.jwt_secret, Permission::CouponRead).await?;
+        let filter = CouponFilter { owner_id: Some(user_id), ..Default::default() };
+        let mut q = coupon_query(active_only, filter, sort);
+        q.services = staff.scope_services(q.services)?;
+        coupon_connection(&st.pool, q, after, before, first, last).await
     }
 
     /// Optional helper: fetch a single coupon by code
@@ -108,33 +858,29 @@ impl QueryRoot {
         &self,
         ctx: &Context<'_>,
         code: String,
-    ) -> GqlResult<Option<Coupon>> {
+    ) -> ApiResult<Option<Coupon>> {
         let st = ctx.data_unchecked::<AppState>();
         Ok(db::get_coupon_by_code(&st.pool, &code).await?.map(db_coupon_to_gql))
     }
-    async fn my_coupons(&self, ctx: &Context<'_>) -> GqlResult<Vec<Coupon>> {
-        use sqlx::Row;
+    /// Active coupons held by the current user, newest first unless `sort` says otherwise.
+    /// Ownership fields in `filter` are ignored.
+    #[allow(clippy::too_many_arguments)]
+    async fn my_coupons(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<CouponFilter>,
+        sort: Option<CouponSort>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> ApiResult<CouponConnection> {
         let st = ctx.data_unchecked::<AppState>();
-        let uid = require_user(ctx, &st.jwt_secret)?;
-        let now = chrono::Utc::now().timestamp();
-        let rows = sqlx::query(
-            "SELECT id,code,description,service,expires_at,owner_id,created_at
-             FROM coupons WHERE owner_id = ? AND expires_at > ? ORDER BY created_at DESC"
-        )
-        .bind(uid)
-        .bind(now)
-        .fetch_all(&st.pool)
-        .await?;
-
-        Ok(rows.into_iter().map(|r| Coupon {
-            id: r.get("id"),
-            code: r.get("code"),
-            description: r.get("description"),
-            service: r.get("service"),
-            expires_at: r.get("expires_at"),
-            owner_id: r.get::<Option<String>,_>("owner_id"),
-            created_at: r.get("created_at"),
-        }).collect())
+        let uid = require_user(ctx, &st.pool, &st.jwt_secret).await?;
+        let mut q = coupon_query(true, filter.unwrap_or_default(), sort);
+        q.owned = None;
+        q.owner_id = Some(uid);
+        coupon_connection(&st.pool, q, after, before, first, last).await
     }
 }
 
@@ -143,68 +889,412 @@ pub struct MutationRoot;
 #[Object]
 impl MutationRoot {
     // -------- Auth --------
-    async fn register(&self, ctx: &Context<'_>, input: RegisterInput) -> GqlResult<User> {
+    /// The email is trimmed and lower-cased; the password has to satisfy the server's policy.
+    /// With email verification on, a taken address looks like a successful signup (its owner is
+    /// told by email instead); otherwise it fails with CONFLICT.
+    async fn register(&self, ctx: &Context<'_>, input: RegisterInput) -> ApiResult<RegisterPayload> {
         let st = ctx.data_unchecked::<AppState>();
+        let email = auth::normalize_email(&input.email)?;
+        st.password_policy.check(&input.password, &email)?;
 
-        if let Some(u) = db::find_user_by_email(&st.pool, &input.email).await? {
-            return Ok(User { id: u.id, email: u.email, is_admin: u.is_admin });
+        // Hashed even for taken addresses, so both answers take as long.
+        let hash = auth::hash_password_throttled(input.password).await?;
+        let created = db::create_user(&st.pool, &email, &hash, st.first_user_admin, &actor(ctx, None)).await?;
+
+        if st.require_email_verification {
+            let state = st.clone();
+            tokio::spawn(async move {
+                let sent = match &created {
+                    Some(user) => send_verification(&state, user).await,
+                    None => send_already_registered(&state, &email).await,
+                };
+                if let Err(e) = sent {
+                    tracing::warn!("registration email failed: {e}");
+                }
+            });
+            return Ok(RegisterPayload { user: None, verification_required: true });
+        }
+        match created {
+            Some(u) => Ok(RegisterPayload { user: Some(gql_user(&st.pool, u).await?), verification_required: false }),
+            None => Err(ApiError::conflict("An account with this email already exists")),
         }
+    }
 
-        let hash = auth::hash_password(&input.password)?;
-        // First-ever user becomes admin (bootstrap)
-        let is_first = !db::first_user_exists(&st.pool).await?;
-        let u = db::create_user(&st.pool, &input.email, &hash, is_first).await?;
+    /// Confirm the email address with the token from the verification email.
+    async fn verify_email(&self, ctx: &Context<'_>, token: String) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        match db::verify_email(&st.pool, &auth::hash_opaque_token(&token), &actor(ctx, None)).await? {
+            Some(_) => Ok(true),
+            None => Err(ApiError::InvalidToken("Invalid or expired verification token".into())),
+        }
+    }
+
+    /// Send a fresh verification email to the current user; earlier links stop working.
+    /// Returns false if the address is already verified.
+    async fn resend_verification(&self, ctx: &Context<'_>) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let uid = require_user(ctx, &st.pool, &st.jwt_secret).await?;
+        let Some(u) = db::find_user_by_id(&st.pool, &uid).await? else {
+            return Err(ApiError::unauthenticated("Unauthorized: unknown user"));
+        };
+        if u.email_verified_at.is_some() {
+            return Ok(false);
+        }
+        send_verification(st, &u).await?;
+        Ok(true)
+    }
+
+    /// Claim a non-expired coupon for the current user. Shareable codes can be held by many users at once.
+    /// Fails with NOT_FOUND, ALREADY_CLAIMED, NOT_YET_VALID, EXPIRED, FULLY_CLAIMED or USAGE_LIMIT_REACHED.
+    async fn claim_coupon(&self, ctx: &Context<'_>, code: String) -> ApiResult<Coupon> {
+        let st = ctx.data_unchecked::<AppState>();
+        let uid = require_user(ctx, &st.pool, &st.jwt_secret).await?;
+        require_verified_email(st, &uid).await?;
+        match db::claim_coupon(&st.pool, &code, &uid, &actor(ctx, Some(&uid))).await? {
+            db::ClaimOutcome::Claimed(c) => {
+                st.publish(CouponEventKind::Claimed, (*c).clone(), vec![uid]);
+                Ok(db_coupon_to_gql(*c))
+            }
+            db::ClaimOutcome::LimitReached(hit) => Err(usage_limit_error(hit)),
+            db::ClaimOutcome::NotFound => Err(coupon_not_found(&code)),
+            db::ClaimOutcome::AlreadyClaimed => Err(ApiError::AlreadyClaimed),
+            db::ClaimOutcome::NotYetValid => Err(ApiError::NotYetValid),
+            db::ClaimOutcome::Expired => Err(ApiError::Expired),
+            db::ClaimOutcome::FullyClaimed => Err(ApiError::FullyClaimed),
+        }
+    }
+
+    /// Redeem a coupon the current user holds. Used-up coupons can't be released or re-claimed.
+    /// Fails with NOT_FOUND, NOT_CLAIMED, NOT_YET_VALID, EXPIRED or USAGE_LIMIT_REACHED.
+    async fn redeem_coupon(&self, ctx: &Context<'_>, code: String) -> ApiResult<Redemption> {
+        let st = ctx.data_unchecked::<AppState>();
+        let uid = require_user(ctx, &st.pool, &st.jwt_secret).await?;
+        require_verified_email(st, &uid).await?;
+        let r = match db::redeem_coupon(&st.pool, &code, &uid, &actor(ctx, Some(&uid))).await? {
+            db::RedeemOutcome::Redeemed(r) => r,
+            db::RedeemOutcome::LimitReached(hit) => return Err(usage_limit_error(hit)),
+            db::RedeemOutcome::NotFound => return Err(coupon_not_found(&code)),
+            db::RedeemOutcome::NotClaimed => return Err(ApiError::NotClaimed),
+            db::RedeemOutcome::NotYetValid => return Err(ApiError::NotYetValid),
+            db::RedeemOutcome::Expired => return Err(ApiError::Expired),
+        };
+        if let Some(c) = db::get_coupon_by_code(&st.pool, &code).await? {
+            st.publish(CouponEventKind::Redeemed, c, vec![uid]);
+        }
+        Ok(Redemption {
+            id: r.id,
+            coupon_id: r.coupon_id,
+            user_id: r.user_id,
+            redeemed_at: r.redeemed_at,
+        })
+    }
 
-        Ok(User { id: u.id, email: u.email, is_admin: u.is_admin })
+    /// Give up the current user's claim on a coupon.
+    async fn release_coupon(&self, ctx: &Context<'_>, code: String) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let uid = require_user(ctx, &st.pool, &st.jwt_secret).await?;
+        let released = db::release_coupon(&st.pool, &code, &uid, &actor(ctx, Some(&uid))).await?;
+        if released {
+            if let Some(c) = db::get_coupon_by_code(&st.pool, &code).await? {
+                st.publish(CouponEventKind::Released, c, vec![uid]);
+            }
+        }
+        Ok(released)
     }
 
-    /// Claim an unowned, non-expired coupon for the current user.
-    async fn claim_coupon(&self, ctx: &Context<'_>, code: String) -> GqlResult<Option<Coupon>> {
+    /// Fails with INVALID_CREDENTIALS on a wrong email or password. Repeated failures for an account
+    /// or from an address lock logins for a while: RATE_LIMITED, with `retry_after` in seconds.
+    async fn login(&self, ctx: &Context<'_>, input: LoginInput) -> ApiResult<AuthPayload> {
         let st = ctx.data_unchecked::<AppState>();
-        let uid = require_user(ctx, &st.jwt_secret)?;
-        let claimed = db::claim_coupon(&st.pool, &code, &uid).await?;
-        Ok(claimed.map(db_coupon_to_gql))
+        let ip = ctx.data_opt::<SocketAddr>().map(|a| a.ip().to_string());
+        let account = auth::canonical_email(&input.email);
+
+        // Reserved before any password hashing, so locked-out guesses cost us nothing.
+        // Unknown emails count too, so lockouts don't reveal which accounts exist.
+        let ip_lock = match &ip {
+            Some(ip) => Some(reserve_login_attempt(st, &auth::IP_THROTTLE, ip).await?),
+            None => None,
+        };
+        let account_lock = match reserve_login_attempt(st, &auth::ACCOUNT_THROTTLE, &account).await {
+            Ok(lock) => lock,
+            Err(e) => {
+                if let (Some(ip), Some(lock)) = (&ip, ip_lock) {
+                    db::release_login_attempt(&st.pool, &auth::IP_THROTTLE, ip, lock).await?;
+                }
+                return Err(e);
+            }
+        };
+
+        let u = db::find_user_by_email(&st.pool, &account).await?;
+        let ok = match &u {
+            Some(u) => auth::verify_password_throttled(u.password_hash.clone(), input.password).await?,
+            None => false,
+        };
+        let Some(u) = u.filter(|_| ok) else {
+            return Err(match account_lock.max(ip_lock.flatten()) {
+                Some(until) => locked_out_error(until),
+                None => ApiError::InvalidCredentials,
+            });
+        };
+        // Right password: neither reservation was a failure after all.
+        if let (Some(ip), Some(lock)) = (&ip, ip_lock) {
+            db::release_login_attempt(&st.pool, &auth::IP_THROTTLE, ip, lock).await?;
+        }
+        db::release_login_attempt(&st.pool, &auth::ACCOUNT_THROTTLE, &account, account_lock).await?;
+        if u.suspended_at.is_some() {
+            return Err(ApiError::forbidden("Forbidden: this account is suspended"));
+        }
+        if u.totp_enabled_at.is_some() {
+            let mfa_token = auth::make_opaque_token();
+            let expires_at = Utc::now().timestamp() + auth::MFA_CHALLENGE_TTL_SECS;
+            db::create_mfa_challenge(&st.pool, &u.id, &auth::hash_opaque_token(&mfa_token), expires_at).await?;
+            return Err(ApiError::MfaRequired { mfa_token });
+        }
+        start_session(st, &u).await
     }
 
-    /// Release a coupon currently owned by the user.
-    async fn release_coupon(&self, ctx: &Context<'_>, code: String) -> GqlResult<bool> {
+    /// Second step of a login with two-factor authentication: the `mfa_token` from login's
+    /// MFA_REQUIRED error and a code from the authenticator app (or an unused recovery code).
+    /// Fails with INVALID_CODE; after a few wrong codes the token stops working (INVALID_TOKEN).
+    /// Wrong codes also count towards the account's login lockout (RATE_LIMITED).
+    async fn verify_two_factor(&self, ctx: &Context<'_>, mfa_token: String, code: String) -> ApiResult<AuthPayload> {
         let st = ctx.data_unchecked::<AppState>();
-        let uid = require_user(ctx, &st.jwt_secret)?;
-        Ok(db::release_coupon(&st.pool, &code, &uid).await?)
+        let token_hash = auth::hash_opaque_token(&mfa_token);
+        let expired = || ApiError::InvalidToken("Invalid or expired login, sign in again".into());
+        let Some(uid) = db::reserve_mfa_attempt(&st.pool, &token_hash).await? else {
+            return Err(expired());
+        };
+        let Some(u) = db::find_user_by_id(&st.pool, &uid).await? else {
+            return Err(expired());
+        };
+        if !check_second_factor(st, &u, &code, &actor(ctx, Some(&uid))).await? {
+            return Err(ApiError::InvalidCode);
+        }
+        if !db::consume_mfa_challenge(&st.pool, &token_hash).await? {
+            return Err(expired());
+        }
+        start_session(st, &u).await
     }
 
-    async fn login(&self, ctx: &Context<'_>, input: LoginInput) -> GqlResult<String> {
+    /// Start turning on two-factor authentication; finish with `confirmTotp`.
+    /// Calling it again before confirming replaces the secret.
+    async fn enroll_totp(&self, ctx: &Context<'_>) -> ApiResult<TotpEnrollment> {
         let st = ctx.data_unchecked::<AppState>();
-        let Some(u) = db::find_user_by_email(&st.pool, &input.email).await? else {
-            return Ok("".into());
+        let uid = require_user(ctx, &st.pool, &st.jwt_secret).await?;
+        let Some(u) = db::find_user_by_id(&st.pool, &uid).await? else {
+            return Err(ApiError::unauthenticated("Unauthorized: unknown user"));
         };
-        if !auth::verify_password(&u.password_hash, &input.password) {
-            return Ok("".into());
+        let secret = auth::make_totp_secret();
+        if !db::start_totp_enrollment(&st.pool, &uid, &secret).await? {
+            return Err(ApiError::conflict("Two-factor authentication is already on"));
         }
-        let token = auth::make_jwt_3min(&st.jwt_secret, &u.id)?;
-        Ok(token)
+        Ok(TotpEnrollment { otpauth_uri: auth::totp_uri(&u.email, &secret), secret })
     }
 
-    // -------- Admin: Coupon CRUD --------
-    async fn create_coupon(&self, ctx: &Context<'_>, input: CreateCouponInput) -> GqlResult<Coupon> {
+    /// Turn two-factor authentication on with a first code from the app. Returns the recovery
+    /// codes; they are shown only this once, and each works once in place of a code.
+    async fn confirm_totp(&self, ctx: &Context<'_>, code: String) -> ApiResult<Vec<String>> {
         let st = ctx.data_unchecked::<AppState>();
-        require_admin(ctx, &st.pool, &st.jwt_secret).await?;
+        let uid = require_user(ctx, &st.pool, &st.jwt_secret).await?;
+        let totp = match db::get_totp(&st.pool, &uid).await? {
+            Some(t) if t.enabled => return Err(ApiError::conflict("Two-factor authentication is already on")),
+            Some(t) => t,
+            None => return Err(ApiError::conflict("Start with enrollTotp")),
+        };
+        let Some(step) = auth::check_totp(&totp.secret, &code, Utc::now().timestamp(), None) else {
+            return Err(ApiError::InvalidCode);
+        };
 
-        let created = db::create_coupon(
+        let codes = auth::make_recovery_codes();
+        let mut hashes = Vec::with_capacity(codes.len());
+        for code in &codes {
+            hashes.push(auth::hash_password_throttled(auth::normalize_recovery_code(code)).await?);
+        }
+        if !db::enable_totp(&st.pool, &uid, step, &hashes, &actor(ctx, Some(&uid))).await? {
+            return Err(ApiError::conflict("Two-factor authentication is already on"));
+        }
+        Ok(codes)
+    }
+
+    /// Turn two-factor authentication off; needs a current code or a recovery code.
+    /// Wrong codes count towards the account's login lockout (RATE_LIMITED).
+    /// Fails with CONFLICT if it isn't on.
+    async fn disable_totp(&self, ctx: &Context<'_>, code: String) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let uid = require_user(ctx, &st.pool, &st.jwt_secret).await?;
+        let Some(u) = db::find_user_by_id(&st.pool, &uid).await? else {
+            return Err(ApiError::unauthenticated("Unauthorized: unknown user"));
+        };
+        if u.totp_enabled_at.is_none() {
+            return Err(ApiError::conflict("Two-factor authentication is not enabled"));
+        }
+        let actor = actor(ctx, Some(&uid));
+        if !check_second_factor(st, &u, &code, &actor).await? {
+            return Err(ApiError::InvalidCode);
+        }
+        Ok(db::disable_totp(&st.pool, &uid, &actor).await?)
+    }
+
+    /// Swap a refresh token for a new access JWT and a new refresh token.
+    /// The old refresh token stops working; replaying it revokes the whole login session.
+    async fn refresh_token(&self, ctx: &Context<'_>, refresh_token: String) -> ApiResult<AuthPayload> {
+        let st = ctx.data_unchecked::<AppState>();
+        let next = auth::make_opaque_token();
+        let outcome = db::rotate_refresh_token(
             &st.pool,
-            &input.code,
-            &input.description,
-            &input.service,
-            input.expires_in_days,
-            input.owner_id.as_deref(),
+            &auth::hash_opaque_token(&refresh_token),
+            &auth::hash_opaque_token(&next),
+            refresh_expires_at(),
         ).await?;
 
+        match outcome {
+            db::RefreshOutcome::Rotated { user_id } => Ok(AuthPayload {
+                access_token: auth::make_jwt_3min(&st.jwt_secret, &user_id)?,
+                refresh_token: next,
+            }),
+            db::RefreshOutcome::Reused => {
+                tracing::warn!("refresh token reuse detected; token family revoked");
+                Err(ApiError::unauthenticated("Unauthorized: refresh token reuse detected"))
+            }
+            db::RefreshOutcome::Invalid => Err(ApiError::unauthenticated("Unauthorized: invalid or expired refresh token")),
+        }
+    }
+
+    /// Emails a single-use reset link if the address belongs to an active account.
+    /// Always returns true so the response doesn't reveal whether the email is registered.
+    async fn request_password_reset(&self, ctx: &Context<'_>, email: String) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>().clone();
+        // Lookup and delivery happen off the request so the response time doesn't give it away either.
+        tokio::spawn(async move {
+            if let Err(e) = send_password_reset(&st, &email).await {
+                tracing::warn!("password reset email failed: {e}");
+            }
+        });
+        Ok(true)
+    }
+
+    /// Set a new password with a token from `requestPasswordReset`. Ends every session of the account.
+    async fn reset_password(&self, ctx: &Context<'_>, token: String, new_password: String) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let token_hash = auth::hash_opaque_token(&token);
+        // The policy needs the account's email, so look the token up before hashing.
+        let Some(u) = db::password_reset_user(&st.pool, &token_hash).await? else {
+            return Err(ApiError::InvalidToken("Invalid or expired reset token".into()));
+        };
+        st.password_policy.check(&new_password, &u.email)?;
+        let hash = auth::hash_password_throttled(new_password).await?;
+        match db::reset_password(&st.pool, &token_hash, &hash, &actor(ctx, None)).await? {
+            Some(uid) => {
+                // The new password works straight away, even if guessing had locked the account.
+                if let Some(u) = db::find_user_by_id(&st.pool, &uid).await? {
+                    db::clear_login_failures(&st.pool, &auth::ACCOUNT_THROTTLE, &auth::canonical_email(&u.email)).await?;
+                }
+                Ok(true)
+            }
+            None => Err(ApiError::InvalidToken("Invalid or expired reset token".into())),
+        }
+    }
+
+    /// Revoke the presented access token. Pass the refresh token too to end the whole session.
+    async fn logout(&self, ctx: &Context<'_>, refresh_token: Option<String>) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let Some(token) = bearer_token_from_ctx(ctx) else {
+            return Err(ApiError::unauthenticated("Unauthorized: missing bearer token"));
+        };
+        let claims = auth::decode_jwt(&st.jwt_secret, &token)?;
+        db::revoke_token(&st.pool, &claims.jti, &claims.sub, claims.exp as i64).await?;
+        if let Some(rt) = refresh_token {
+            db::revoke_refresh_family(&st.pool, &auth::hash_opaque_token(&rt), &claims.sub).await?;
+        }
+        Ok(true)
+    }
+
+    // -------- Staff: Coupon CRUD (each needs its coupon:* permission) --------
+    async fn create_coupon(&self, ctx: &Context<'_>, input: CreateCouponInput) -> ApiResult<Coupon> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff = require_staff(ctx, &st.pool, &st.jwt_secret, Permission::CouponCreate).await?;
+        staff.check_service(&input.service)?;
+
+        let discount = input.discount.map(gql_discount_to_domain).transpose()?;
+        let limits = db::UsageLimits {
+            max_redemptions: usage_cap(input.max_redemptions)?,
+            max_per_user: usage_cap(input.max_redemptions_per_user)?,
+            max_claims: usage_cap(input.max_claims)?,
+        };
+        let Some(expires_at) = expiry(input.expires_at, input.expires_in_days)? else {
+            return Err(ApiError::validation("Give either expiresAt or expiresInDays"));
+        };
+        let valid_from = input.valid_from.map_or_else(|| Utc::now().timestamp(), |t| t.timestamp());
+        validity_window(valid_from, expires_at)?;
+        let created = db::create_coupon(
+            &st.pool,
+            &db::NewCoupon {
+                code: &input.code,
+                description: &input.description,
+                service: &input.service,
+                valid_from,
+                expires_at,
+                owner_id: input.owner_id.as_deref(),
+                discount: discount.as_ref(),
+                limits,
+            },
+            &staff_actor(ctx, &staff),
+        ).await.map_err(coupon_write_error)?;
+
+        st.publish(CouponEventKind::Created, created.clone(), input.owner_id.into_iter().collect());
         Ok(db_coupon_to_gql(created))
     }
 
-    async fn update_coupon(&self, ctx: &Context<'_>, input: UpdateCouponInput) -> GqlResult<bool> {
+    /// Create `count` unclaimed coupons with random codes following `pattern`, all or nothing.
+    async fn generate_coupons(&self, ctx: &Context<'_>, input: GenerateCouponsInput) -> ApiResult<CouponBatch> {
         let st = ctx.data_unchecked::<AppState>();
-        require_admin(ctx, &st.pool, &st.jwt_secret).await?;
+        let staff = require_staff(ctx, &st.pool, &st.jwt_secret, Permission::CouponCreate).await?;
+        staff.check_service(&input.service)?;
+
+        if input.count < 1 || input.count as usize > MAX_BATCH_SIZE {
+            return Err(ApiError::validation(format!("count must be between 1 and {MAX_BATCH_SIZE}")));
+        }
+        let count = input.count as usize;
+        let pattern = codes::CodePattern::parse(&input.pattern, input.alphabet.as_deref())
+            .map_err(|e| ApiError::validation(e.to_string()))?;
+        if !pattern.has_room_for(count) {
+            return Err(ApiError::validation(
+                "Invalid pattern: too few possible codes for this count; add more X placeholders",
+            ));
+        }
+        let discount = input.discount.map(gql_discount_to_domain).transpose()?;
+        let Some(expires_at) = expiry(input.expires_at, input.expires_in_days)? else {
+            return Err(ApiError::validation("Give either expiresAt or expiresInDays"));
+        };
+        let valid_from = input.valid_from.map_or_else(|| Utc::now().timestamp(), |t| t.timestamp());
+        validity_window(valid_from, expires_at)?;
+
+        let batch = db::create_coupon_batch(
+            &st.pool,
+            &db::NewCouponBatch {
+                pattern: &input.pattern,
+                count,
+                description: &input.description,
+                service: &input.service,
+                valid_from,
+                expires_at,
+                discount: discount.as_ref(),
+                created_by: staff.user_id(),
+            },
+            || pattern.generate(),
+            &staff_actor(ctx, &staff),
+        ).await?;
+
+        st.publish_batch(batch.clone());
+        Ok(db_batch_to_gql(batch))
+    }
+
+    async fn update_coupon(&self, ctx: &Context<'_>, input: UpdateCouponInput) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff = require_staff(ctx, &st.pool, &st.jwt_secret, Permission::CouponUpdate).await?;
+        if let Some(service) = &input.service {
+            staff.check_service(service)?;
+        }
 
         // Determine owner patch
         let owner_patch: Option<Option<&str>> = if let Some(owner) = input.owner_id.as_deref() {
@@ -214,32 +1304,326 @@ impl MutationRoot {
         } else {
             None
         };
+        let discount = input.discount.map(gql_discount_to_domain).transpose()?;
+        let discount_patch: Option<Option<&discount::Discount>> = if let Some(d) = discount.as_ref() {
+            Some(Some(d))
+        } else if input.clear_discount.unwrap_or(false) {
+            Some(None)
+        } else {
+            None
+        };
+        let limits_patch = db::UsageLimitsPatch {
+            max_redemptions: usage_cap_patch(input.max_redemptions, input.clear_max_redemptions)?,
+            max_per_user: usage_cap_patch(input.max_redemptions_per_user, input.clear_max_redemptions_per_user)?,
+            max_claims: usage_cap_patch(input.max_claims, input.clear_max_claims)?,
+        };
 
+        let holders_before = match db::get_coupon_by_code(&st.pool, &input.code).await? {
+            Some(c) => {
+                staff.check_service(&c.service)?;
+                db::coupon_holders(&st.pool, &c.id).await?
+            }
+            None => Vec::new(),
+        };
         let ok = db::update_coupon_by_code(
             &st.pool,
             &input.code,
-            input.description.as_deref(),
-            input.service.as_deref(),
-            input.expires_in_days,
-            owner_patch,
-        ).await?;
+            &db::CouponPatch {
+                description: input.description.as_deref(),
+                service: input.service.as_deref(),
+                valid_from: input.valid_from.map(|t| t.timestamp()),
+                expires_at: expiry(input.expires_at, input.expires_in_days)?,
+                owner_id: owner_patch,
+                discount: discount_patch,
+                limits: limits_patch,
+            },
+            &staff_actor(ctx, &staff),
+        ).await.map_err(coupon_write_error)?;
+        if ok {
+            if let Some(after) = db::get_coupon_by_code(&st.pool, &input.code).await? {
+                let mut user_ids = holders_before;
+                for h in db::coupon_holders(&st.pool, &after.id).await? {
+                    if !user_ids.contains(&h) { user_ids.push(h); }
+                }
+                st.publish(CouponEventKind::Updated, after, user_ids);
+            }
+        }
         Ok(ok)
     }
 
-    async fn delete_coupon(&self, ctx: &Context<'_>, code: String) -> GqlResult<bool> {
+    /// Archive the coupon; it disappears from every public query until `restoreCoupon`.
+    async fn delete_coupon(&self, ctx: &Context<'_>, code: String) -> ApiResult<bool> {
         let st = ctx.data_unchecked::<AppState>();
-        require_admin(ctx, &st.pool, &st.jwt_secret).await?;
-        Ok(db::delete_coupon_by_code(&st.pool, &code).await?)
+        let staff = require_staff(ctx, &st.pool, &st.jwt_secret, Permission::CouponDelete).await?;
+        if let Some(c) = db::get_coupon_by_code(&st.pool, &code).await? {
+            staff.check_service(&c.service)?;
+        }
+        let deleted = db::archive_coupon_by_code(&st.pool, &code, &staff_actor(ctx, &staff)).await?;
+        if deleted {
+            if let Some(c) = db::get_archived_coupon_by_code(&st.pool, &code).await? {
+                let holders = db::coupon_holders(&st.pool, &c.id).await?;
+                st.publish(CouponEventKind::Deleted, c, holders);
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Bring an archived coupon back, with its holders and redemption history.
+    async fn restore_coupon(&self, ctx: &Context<'_>, code: String) -> ApiResult<Option<Coupon>> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff = require_staff(ctx, &st.pool, &st.jwt_secret, Permission::CouponDelete).await?;
+        if let Some(c) = db::get_archived_coupon_by_code(&st.pool, &code).await? {
+            staff.check_service(&c.service)?;
+        }
+        if !db::restore_coupon_by_code(&st.pool, &code, &staff_actor(ctx, &staff)).await? {
+            return Ok(None);
+        }
+        let Some(c) = db::get_coupon_by_code(&st.pool, &code).await? else { return Ok(None); };
+        let holders = db::coupon_holders(&st.pool, &c.id).await?;
+        st.publish(CouponEventKind::Restored, c.clone(), holders);
+        Ok(Some(db_coupon_to_gql(c)))
+    }
+
+    /// Permanently remove an archived coupon with its claims and redemptions.
+    /// Live coupons must be deleted (archived) first.
+    async fn purge_coupon(&self, ctx: &Context<'_>, code: String) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff = require_staff(ctx, &st.pool, &st.jwt_secret, Permission::CouponPurge).await?;
+        if db::get_coupon_by_code(&st.pool, &code).await?.is_some() {
+            return Err(ApiError::conflict("Archive this coupon with deleteCoupon before purging it"));
+        }
+        if let Some(c) = db::get_archived_coupon_by_code(&st.pool, &code).await? {
+            staff.check_service(&c.service)?;
+        }
+        Ok(db::purge_coupon_by_code(&st.pool, &code, &staff_actor(ctx, &staff)).await?)
+    }
+
+    /// Make the current user the first superadmin with the setup token printed at startup
+    /// (or given as SETUP_TOKEN). Works once, and only while there is no superadmin.
+    async fn bootstrap_admin(&self, ctx: &Context<'_>, setup_token: String) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let uid = require_user(ctx, &st.pool, &st.jwt_secret).await?;
+        // Held until the grant is done, so the token can't be used twice.
+        let mut slot = st.setup_token.lock().await;
+        let matches = slot
+            .as_deref()
+            .is_some_and(|t| auth::hash_opaque_token(t) == auth::hash_opaque_token(&setup_token));
+        if !matches {
+            return Err(ApiError::InvalidToken("Invalid or already used setup token".into()));
+        }
+        if !db::bootstrap_superadmin(&st.pool, &uid, &actor(ctx, Some(&uid))).await? {
+            *slot = None;
+            return Err(ApiError::conflict("A superadmin already exists"));
+        }
+        *slot = None;
+        tracing::info!(user_id = %uid, "superadmin bootstrapped; setup token used up");
+        Ok(true)
+    }
+
+    // -------- API keys (user:manage) --------
+    /// A key for a merchant's backend, limited to `services` and `permissions`.
+    /// The key is returned only here; just a hash of it is stored.
+    async fn create_api_key(&self, ctx: &Context<'_>, input: CreateApiKeyInput) -> ApiResult<NewApiKey> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff_id = require_permission(ctx, &st.pool, &st.jwt_secret, Permission::UserManage).await?;
+
+        let name = input.name.trim();
+        if name.is_empty() {
+            return Err(ApiError::validation("Give the API key a name"));
+        }
+        let services: Vec<String> = input.services.iter().map(|s| s.trim().to_string()).collect();
+        if services.is_empty() || services.iter().any(String::is_empty) {
+            return Err(ApiError::validation("Give at least one service, none of them empty"));
+        }
+        let mut permissions = Vec::with_capacity(input.permissions.len());
+        for p in &input.permissions {
+            match Permission::parse(p.trim()) {
+                Some(p) if authz::API_KEY_PERMISSIONS.contains(&p) => permissions.push(p),
+                _ => return Err(ApiError::validation(format!("API keys can't have the permission {p:?}"))),
+            }
+        }
+        if permissions.is_empty() {
+            return Err(ApiError::validation("Give at least one permission"));
+        }
+
+        let key = auth::make_api_key();
+        let created = db::create_api_key(
+            &st.pool,
+            name,
+            &auth::hash_opaque_token(&key),
+            &key[..auth::API_KEY_PREFIX_LEN],
+            &services,
+            &permissions,
+            &actor(ctx, Some(&staff_id)),
+        ).await?;
+        Ok(NewApiKey { key, api_key: db_api_key_to_gql(created) })
+    }
+
+    /// Stops the key working straight away. Returns false if unknown or already revoked.
+    async fn revoke_api_key(&self, ctx: &Context<'_>, id: String) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff_id = require_permission(ctx, &st.pool, &st.jwt_secret, Permission::UserManage).await?;
+        Ok(db::revoke_api_key(&st.pool, &id, &actor(ctx, Some(&staff_id))).await?)
+    }
+
+    // -------- Roles (user:manage) --------
+    /// Returns false if the user already has the role.
+    async fn assign_role(&self, ctx: &Context<'_>, user_id: String, role: String) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff_id = require_permission(ctx, &st.pool, &st.jwt_secret, Permission::UserManage).await?;
+        Ok(db::assign_role(&st.pool, &user_id, &role, &actor(ctx, Some(&staff_id))).await?)
+    }
+
+    /// Returns false if the user doesn't have the role. The last active superadmin can't lose theirs.
+    async fn revoke_role(&self, ctx: &Context<'_>, user_id: String, role: String) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff_id = require_permission(ctx, &st.pool, &st.jwt_secret, Permission::UserManage).await?;
+        Ok(db::revoke_role(&st.pool, &user_id, &role, &actor(ctx, Some(&staff_id))).await?)
+    }
+
+    // -------- Accounts (user:manage) --------
+    /// Blocks login and every token the user holds. Returns false if unknown or already suspended.
+    async fn suspend_user(&self, ctx: &Context<'_>, user_id: String, reason: Option<String>) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff_id = require_permission(ctx, &st.pool, &st.jwt_secret, Permission::UserManage).await?;
+        if user_id == staff_id {
+            return Err(ApiError::forbidden("You can't suspend your own account"));
+        }
+        Ok(db::suspend_user(&st.pool, &user_id, reason.as_deref(), &actor(ctx, Some(&staff_id))).await?)
+    }
+
+    /// Lift a login lockout from repeated failed passwords. Returns false if unknown or not locked.
+    async fn unlock_account(&self, ctx: &Context<'_>, user_id: String) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff_id = require_permission(ctx, &st.pool, &st.jwt_secret, Permission::UserManage).await?;
+        Ok(db::unlock_account(&st.pool, &user_id, &actor(ctx, Some(&staff_id))).await?)
+    }
+
+    /// Returns false if unknown or not suspended.
+    async fn unsuspend_user(&self, ctx: &Context<'_>, user_id: String) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff_id = require_permission(ctx, &st.pool, &st.jwt_secret, Permission::UserManage).await?;
+        Ok(db::unsuspend_user(&st.pool, &user_id, &actor(ctx, Some(&staff_id))).await?)
+    }
+
+    /// Permanently delete the account with its roles, claims, redemptions and sessions.
+    async fn delete_user(&self, ctx: &Context<'_>, user_id: String) -> ApiResult<bool> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff_id = require_permission(ctx, &st.pool, &st.jwt_secret, Permission::UserManage).await?;
+        if user_id == staff_id {
+            return Err(ApiError::forbidden("You can't delete your own account"));
+        }
+        Ok(db::delete_user(&st.pool, &user_id, &actor(ctx, Some(&staff_id))).await?)
     }
 }
 
-fn require_user(ctx: &Context<'_>, secret: &str) -> anyhow::Result<String> {
-    if let Some(uid) = user_id_from_headers(ctx, secret)? {
-        Ok(uid)
-    } else {
-        anyhow::bail!("Unauthorized: missing bearer token");
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Staff stream: every coupon lifecycle event, optionally limited to one `service`.
+    /// Needs `coupon:read`, since it includes archived coupons and their owners.
+    async fn coupon_events(
+        &self,
+        ctx: &Context<'_>,
+        service: Option<String>,
+    ) -> async_graphql::Result<impl Stream<Item = CouponEvent>> {
+        let st = ctx.data_unchecked::<AppState>();
+        let staff = require_staff(ctx, &st.pool, &st.jwt_secret, Permission::CouponRead).await?;
+        let services = staff.scope_services(service.into_iter().collect())?;
+        Ok(guarded_events(st, Subscriber::from_ctx(ctx, Some(Permission::CouponRead)), move |ev| {
+            services.is_empty() || services.iter().any(|s| s == ev.service())
+        }))
+    }
+
+    /// Private stream: events for coupons the current user owns, or just stopped owning.
+    async fn my_coupon_events(&self, ctx: &Context<'_>) -> async_graphql::Result<impl Stream<Item = CouponEvent>> {
+        let st = ctx.data_unchecked::<AppState>();
+        let uid = require_user(ctx, &st.pool, &st.jwt_secret).await?;
+        Ok(guarded_events(st, Subscriber::from_ctx(ctx, None), move |ev| ev.user_ids.contains(&uid)))
+    }
+}
+
+// How often an idle subscription re-checks its credentials.
+const SUBSCRIPTION_RECHECK: std::time::Duration = std::time::Duration::from_secs(30);
+
+// The credentials a subscription was opened with, checked again while it runs: before each
+// event it delivers and every SUBSCRIPTION_RECHECK. The stream ends once the token expires or
+// is revoked, the account is suspended or loses `permission`, or the API key is revoked.
+struct Subscriber {
+    bearer: Option<String>,
+    api_key: Option<String>,
+    permission: Option<Permission>, // None = any signed-in user
+}
+
+impl Subscriber {
+    fn from_ctx(ctx: &Context<'_>, permission: Option<Permission>) -> Self {
+        Subscriber { bearer: bearer_token_from_ctx(ctx), api_key: api_key_from_ctx(ctx), permission }
+    }
+
+    // Same rules as `require_staff` / `require_user`.
+    async fn check(&self, st: &AppState) -> ApiResult<()> {
+        if let (None, Some(key), Some(permission)) = (&self.bearer, &self.api_key, self.permission) {
+            let k = auth::parse_api_key(&st.pool, key).await?;
+            if !k.permissions.iter().any(|p| p == permission.as_str()) {
+                return Err(ApiError::forbidden("Forbidden: this API key lost its permission"));
+            }
+            return Ok(());
+        }
+        let Some(token) = &self.bearer else {
+            return Err(ApiError::unauthenticated("Unauthorized: missing bearer token"));
+        };
+        let uid = auth::parse_jwt(&st.pool, &st.jwt_secret, token).await?;
+        if let Some(permission) = self.permission {
+            if !db::user_has_permission(&st.pool, &uid, permission).await? {
+                return Err(ApiError::forbidden(format!("Forbidden: requires the {} permission", permission.as_str())));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Coupon events `wanted` by a subscriber, for as long as `who` still checks out.
+fn guarded_events(
+    st: &AppState,
+    who: Subscriber,
+    wanted: impl Fn(&CouponEvent) -> bool + Send + 'static,
+) -> impl Stream<Item = CouponEvent> {
+    let st = st.clone();
+    let mut rx = st.events.subscribe();
+    async_graphql::async_stream::stream! {
+        let mut recheck = tokio::time::interval(SUBSCRIPTION_RECHECK);
+        recheck.tick().await; // the first tick is immediate; the subscriber was just checked
+        loop {
+            let ev = tokio::select! {
+                ev = rx.recv() => match ev {
+                    Ok(ev) if wanted(&ev) => Some(ev),
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue, // drop lag notifications
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = recheck.tick() => None,
+            };
+            if who.check(&st).await.is_err() {
+                break;
+            }
+            if let Some(ev) = ev {
+                yield ev;
+            }
+        }
     }
 }
+
+async fn require_user(ctx: &Context<'_>, pool: &SqlitePool, secret: &str) -> ApiResult<String> {
+    user_id_from_headers(ctx, pool, secret)
+        .await?
+        .ok_or_else(|| ApiError::unauthenticated("Unauthorized: missing bearer token"))
+}
+async fn require_verified_email(st: &AppState, user_id: &str) -> ApiResult<()> {
+    if st.require_email_verification && !db::is_email_verified(&st.pool, user_id).await? {
+        return Err(ApiError::forbidden("Forbidden: verify your email address first (see resendVerification)"));
+    }
+    Ok(())
+}
 // ---------- Helpers ----------
 fn bearer_token_from_ctx(ctx: &Context<'_>) -> Option<String> {
     ctx.data_opt::<axum::http::HeaderMap>()?
@@ -249,32 +1633,458 @@ fn bearer_token_from_ctx(ctx: &Context<'_>) -> Option<String> {
         .map(|s| s.to_string())
 }
 
-fn user_id_from_headers(ctx: &Context<'_>, secret: &str) -> anyhow::Result<Option<String>> {
+fn api_key_from_ctx(ctx: &Context<'_>) -> Option<String> {
+    ctx.data_opt::<axum::http::HeaderMap>()?
+        .get(auth::API_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.trim().to_string())
+}
+
+async fn user_id_from_headers(ctx: &Context<'_>, pool: &SqlitePool, secret: &str) -> ApiResult<Option<String>> {
     if let Some(token) = bearer_token_from_ctx(ctx) {
-        Ok(Some(auth::parse_jwt(secret, &token)?))
+        Ok(Some(auth::parse_jwt(pool, secret, &token).await?))
     } else {
         Ok(None)
     }
 }
 
-async fn require_admin(ctx: &Context<'_>, pool: &SqlitePool, secret: &str) -> anyhow::Result<()> {
-    let Some(user_id) = user_id_from_headers(ctx, secret)? else {
-        anyhow::bail!("Unauthorized: missing bearer token");
+// Audit-log actor for a mutation: `user_id` (if signed in) and the client's address.
+fn actor(ctx: &Context<'_>, user_id: Option<&str>) -> db::Actor {
+    db::Actor {
+        user_id: user_id.map(str::to_string),
+        api_key_id: None,
+        ip: ctx.data_opt::<SocketAddr>().map(|a| a.ip().to_string()),
+    }
+}
+
+fn staff_actor(ctx: &Context<'_>, staff: &Principal) -> db::Actor {
+    db::Actor {
+        api_key_id: staff.api_key_id().map(str::to_string),
+        ..actor(ctx, staff.user_id())
+    }
+}
+
+// Returns the caller's user id if one of their roles grants `permission`.
+async fn require_permission(
+    ctx: &Context<'_>,
+    pool: &SqlitePool,
+    secret: &str,
+    permission: Permission,
+) -> ApiResult<String> {
+    let user_id = require_user(ctx, pool, secret).await?;
+    if !db::user_has_permission(pool, &user_id, permission).await? {
+        return Err(ApiError::forbidden(format!("Forbidden: requires the {} permission", permission.as_str())));
+    }
+    Ok(user_id)
+}
+
+// Like `require_permission`, but a merchant API key holding `permission` will do too.
+// A bearer token wins if both are sent.
+async fn require_staff(
+    ctx: &Context<'_>,
+    pool: &SqlitePool,
+    secret: &str,
+    permission: Permission,
+) -> ApiResult<Principal> {
+    if let (None, Some(key)) = (bearer_token_from_ctx(ctx), api_key_from_ctx(ctx)) {
+        let k = auth::parse_api_key(pool, &key).await?;
+        if !k.permissions.iter().any(|p| p == permission.as_str()) {
+            return Err(ApiError::forbidden(format!(
+                "Forbidden: this API key lacks the {} permission",
+                permission.as_str()
+            )));
+        }
+        return Ok(Principal::ApiKey { id: k.id, services: k.services });
+    }
+    Ok(Principal::User(require_permission(ctx, pool, secret, permission).await?))
+}
+
+async fn gql_user(pool: &SqlitePool, u: db::DbUser) -> anyhow::Result<User> {
+    Ok(User {
+        roles: db::user_roles(pool, &u.id).await?,
+        permissions: db::user_permissions(pool, &u.id).await?,
+        login_locked_until: db::login_locked_until(pool, &auth::ACCOUNT_THROTTLE, &auth::canonical_email(&u.email)).await?,
+        id: u.id,
+        email: u.email,
+        created_at: u.created_at,
+        suspended_at: u.suspended_at,
+        suspended_reason: u.suspended_reason,
+        email_verified_at: u.email_verified_at,
+        totp_enabled_at: u.totp_enabled_at,
+    })
+}
+
+pub(crate) fn coupon_query(active_only: bool, f: CouponFilter, sort: Option<CouponSort>) -> db::CouponQuery {
+    let mut services = f.services.unwrap_or_default();
+    services.extend(f.service);
+    let (sort, descending) = match sort {
+        Some(s) => (
+            match s.field {
+                CouponSortField::CreatedAt => db::CouponSortKey::CreatedAt,
+                CouponSortField::ExpiresAt => db::CouponSortKey::ExpiresAt,
+                CouponSortField::Code => db::CouponSortKey::Code,
+                CouponSortField::Service => db::CouponSortKey::Service,
+            },
+            s.direction == SortDirection::Desc,
+        ),
+        None => (db::CouponSortKey::CreatedAt, true),
     };
-    if !db::is_user_admin(pool, &user_id).await? {
-        anyhow::bail!("Forbidden: admin required");
+    db::CouponQuery {
+        active_only,
+        services,
+        code_prefix: f.code_prefix,
+        description_contains: f.description_contains,
+        expires_after: f.expires_after,
+        expires_before: f.expires_before,
+        created_after: f.created_after,
+        created_before: f.created_before,
+        owned: f.owned,
+        owner_id: f.owner_id,
+        batch_id: f.batch_id,
+        archived: false,
+        sort,
+        descending,
+    }
+}
+
+// Relay-style keyset pagination shared by the coupon listings.
+async fn coupon_connection(
+    pool: &SqlitePool,
+    q: db::CouponQuery,
+    after: Option<String>,
+    before: Option<String>,
+    first: Option<i32>,
+    last: Option<i32>,
+) -> ApiResult<CouponConnection> {
+    Ok(connection::query(after, before, first, last, |after, before, first, last| async move {
+        let from_end = last.is_some() && first.is_none();
+        let limit = first.or(last).unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE) as i64;
+
+        let (rows, has_more) =
+            db::list_coupons(pool, &q, after.as_ref(), before.as_ref(), limit, from_end).await?;
+        let sort = q.sort;
+        let total_count = db::count_coupons(pool, &q).await?;
+
+        let (has_previous_page, has_next_page) = if from_end {
+            (has_more, before.is_some())
+        } else {
+            (after.is_some(), has_more)
+        };
+        let mut conn = Connection::with_additional_fields(
+            has_previous_page,
+            has_next_page,
+            CouponConnectionFields { total_count },
+        );
+        conn.edges.extend(rows.into_iter().map(|c| {
+            Edge::new(db::CouponCursor::for_coupon(&c, sort), db_coupon_to_gql(c))
+        }));
+        Ok::<_, ApiError>(conn)
+    })
+    .await?)
+}
+
+// Checks `code` is the caller's live coupon and works out what it takes off the order.
+async fn coupon_discount_for_order(
+    pool: &SqlitePool,
+    uid: &str,
+    code: &str,
+    currency: &str,
+    items: &[discount::LineItem],
+    subtotal: Decimal,
+    shipping: Decimal,
+) -> anyhow::Result<Result<Decimal, CouponRejection>> {
+    use CouponRejectionReason as R;
+    let reject = |reason, message: String| Ok(Err(CouponRejection { reason, message }));
+
+    let Some(c) = db::get_coupon_by_code(pool, code).await? else {
+        return reject(R::NotFound, format!("No coupon with code {code}"));
+    };
+    if !db::has_claim(pool, &c.id, uid).await? {
+        return reject(R::NotOwned, "Claim this coupon before using it".into());
+    }
+    let now = Utc::now().timestamp();
+    if c.valid_from > now {
+        let start = DateTime::from_timestamp(c.valid_from, 0).unwrap_or_default();
+        return reject(R::NotYetValid, format!("This coupon is valid from {}", start.to_rfc3339()));
+    }
+    if c.expires_at <= now {
+        return reject(R::Expired, "This coupon has expired".into());
+    }
+    match db::usage_limit_reached(pool, &c, uid).await? {
+        Some(db::LimitReached::Total) => {
+            return reject(R::UsageLimitReached, "This coupon has no uses left".into());
+        }
+        Some(db::LimitReached::PerUser) => {
+            return reject(R::AlreadyRedeemed, "You have already used this coupon as often as allowed".into());
+        }
+        None => {}
+    }
+    let Some(d) = c.discount else {
+        return reject(R::NoDiscountTerms, "This coupon has no discount that can be applied automatically".into());
+    };
+
+    match d.amount_off(currency, items, subtotal, shipping) {
+        Ok(off) => Ok(Ok(off)),
+        Err(discount::NotApplicable::CurrencyMismatch { expected }) => {
+            reject(R::CurrencyMismatch, format!("This coupon is only valid for {expected} orders"))
+        }
+        Err(discount::NotApplicable::MinimumNotMet { minimum }) => {
+            reject(R::MinimumNotMet, format!("Order subtotal must be at least {minimum} {currency}"))
+        }
+        Err(discount::NotApplicable::ItemNotInCart { sku }) => {
+            reject(R::ItemNotInCart, format!("Add {sku} to the order to use this coupon"))
+        }
+    }
+}
+
+fn locked_out_error(until: i64) -> ApiError {
+    ApiError::RateLimited { retry_after: (until - Utc::now().timestamp()).max(1) }
+}
+
+fn coupon_not_found(code: &str) -> ApiError {
+    ApiError::not_found(format!("No coupon with code {code}"))
+}
+
+fn usage_limit_error(hit: db::LimitReached) -> ApiError {
+    ApiError::UsageLimitReached(match hit {
+        db::LimitReached::Total => "Usage limit reached: this coupon has no uses left".into(),
+        db::LimitReached::PerUser => "Usage limit reached: you have already used this coupon as often as allowed".into(),
+    })
+}
+
+// Duplicate codes and unknown owners are the client's problem; anything else is internal.
+fn coupon_write_error(e: anyhow::Error) -> ApiError {
+    db::coupon_constraint_error(&e).unwrap_or_else(|| e.into())
+}
+
+// Caps must be positive; None = unlimited.
+fn usage_cap(v: Option<i32>) -> ApiResult<Option<i64>> {
+    match v {
+        Some(n) if n < 1 => Err(ApiError::validation("Usage limits must be at least 1 (use null for unlimited)")),
+        v => Ok(v.map(i64::from)),
+    }
+}
+
+// A new cap takes precedence over `clear` (make it unlimited).
+fn usage_cap_patch(v: Option<i32>, clear: Option<bool>) -> ApiResult<Option<Option<i64>>> {
+    match v {
+        Some(_) => Ok(Some(usage_cap(v)?)),
+        None if clear.unwrap_or(false) => Ok(Some(None)),
+        None => Ok(None),
+    }
+}
+
+// Absolute expiry from either input form; None if neither was given.
+fn expiry(expires_at: Option<DateTime<Utc>>, expires_in_days: Option<i64>) -> ApiResult<Option<i64>> {
+    match (expires_at, expires_in_days) {
+        (Some(_), Some(_)) => Err(ApiError::validation("Give either expiresAt or expiresInDays, not both")),
+        (Some(t), None) => Ok(Some(t.timestamp())),
+        (None, Some(days)) => match Duration::try_days(days) {
+            Some(d) => Ok(Some((Utc::now() + d).timestamp())),
+            None => Err(ApiError::validation("expiresInDays is out of range")),
+        },
+        (None, None) => Ok(None),
+    }
+}
+
+fn validity_window(valid_from: i64, expires_at: i64) -> ApiResult<()> {
+    if expires_at <= valid_from {
+        return Err(ApiError::validation("Invalid validity window: expiry must be after the start"));
     }
     Ok(())
 }
 
+async fn send_password_reset(st: &AppState, email: &str) -> anyhow::Result<()> {
+    let email = auth::canonical_email(email);
+    let Some(u) = db::find_user_by_email(&st.pool, &email).await? else { return Ok(()); };
+    if u.suspended_at.is_some() {
+        return Ok(());
+    }
+    let token = auth::make_opaque_token();
+    let expires_at = Utc::now().timestamp() + auth::PASSWORD_RESET_TTL_SECS;
+    db::create_password_reset(&st.pool, &u.id, &auth::hash_opaque_token(&token), expires_at).await?;
+
+    let body = format!(
+        "Someone asked to reset the password for this account.\n\n\
+         Reset it here within the next hour:\n{}/reset-password?token={token}\n\n\
+         or use this token with the resetPassword mutation:\n{token}\n\n\
+         If it wasn't you, ignore this email; your password stays the same.\n",
+        st.public_url.trim_end_matches('/'),
+    );
+    st.mailer
+        .send(mail::Email { to: u.email, subject: "Reset your password".into(), body })
+        .await
+}
+
+async fn send_verification(st: &AppState, u: &db::DbUser) -> anyhow::Result<()> {
+    let token = auth::make_opaque_token();
+    let expires_at = Utc::now().timestamp() + auth::EMAIL_VERIFICATION_TTL_SECS;
+    db::create_email_verification(&st.pool, &u.id, &auth::hash_opaque_token(&token), expires_at).await?;
+
+    let body = format!(
+        "Confirm your email address within the next two days:\n{}/verify-email?token={token}\n\n\
+         or use this token with the verifyEmail mutation:\n{token}\n\n\
+         If you didn't create an account, ignore this email.\n",
+        st.public_url.trim_end_matches('/'),
+    );
+    st.mailer
+        .send(mail::Email { to: u.email.clone(), subject: "Confirm your email address".into(), body })
+        .await
+}
+
+// Someone tried to sign up with an address that already has an account.
+async fn send_already_registered(st: &AppState, email: &str) -> anyhow::Result<()> {
+    let body = format!(
+        "Someone tried to create an account with this email address, but you already have one.\n\n\
+         If it was you, sign in at {} instead; if you forgot your password, ask for a reset there.\n\n\
+         If it wasn't you, ignore this email; nothing has changed.\n",
+        st.public_url.trim_end_matches('/'),
+    );
+    st.mailer
+        .send(mail::Email { to: email.to_string(), subject: "You already have an account".into(), body })
+        .await
+}
+
+// A fresh access JWT plus refresh token, once every factor has checked out.
+// Only now are the account's failed logins forgotten.
+async fn start_session(st: &AppState, u: &db::DbUser) -> ApiResult<AuthPayload> {
+    db::clear_login_failures(&st.pool, &auth::ACCOUNT_THROTTLE, &auth::canonical_email(&u.email)).await?;
+    let access_token = auth::make_jwt_3min(&st.jwt_secret, &u.id)?;
+    let refresh_token = auth::make_opaque_token();
+    db::create_refresh_token(
+        &st.pool,
+        &u.id,
+        &auth::hash_opaque_token(&refresh_token),
+        refresh_expires_at(),
+    ).await?;
+    Ok(AuthPayload { access_token, refresh_token })
+}
+
+// Counts an attempt against `subject` up front; RATE_LIMITED while it's locked out.
+// Returns the lockout end if this attempt started one.
+async fn reserve_login_attempt(st: &AppState, throttle: &auth::LoginThrottle, subject: &str) -> ApiResult<Option<i64>> {
+    match db::reserve_login_attempt(&st.pool, throttle, subject).await? {
+        db::LoginAttempt::Allowed(lock) => Ok(lock),
+        db::LoginAttempt::Refused(until) => Err(locked_out_error(until)),
+    }
+}
+
+// A current authenticator code, or failing that an unused recovery code (which gets used up).
+// Wrong codes count as failed logins for the account, so guessing runs into the same lockout.
+async fn check_second_factor(st: &AppState, u: &db::DbUser, code: &str, actor: &db::Actor) -> ApiResult<bool> {
+    let account = auth::canonical_email(&u.email);
+    let lock = reserve_login_attempt(st, &auth::ACCOUNT_THROTTLE, &account).await?;
+    if second_factor_matches(st, &u.id, code, actor).await? {
+        db::release_login_attempt(&st.pool, &auth::ACCOUNT_THROTTLE, &account, lock).await?;
+        return Ok(true);
+    }
+    match lock {
+        Some(until) => Err(locked_out_error(until)),
+        None => Ok(false),
+    }
+}
+
+async fn second_factor_matches(st: &AppState, user_id: &str, code: &str, actor: &db::Actor) -> ApiResult<bool> {
+    let Some(totp) = db::get_totp(&st.pool, user_id).await?.filter(|t| t.enabled) else {
+        return Ok(false);
+    };
+    if let Some(step) = auth::check_totp(&totp.secret, code, Utc::now().timestamp(), totp.last_step) {
+        return Ok(db::use_totp_step(&st.pool, user_id, step).await?);
+    }
+    let code = auth::normalize_recovery_code(code);
+    if code.len() != auth::RECOVERY_CODE_LEN {
+        return Ok(false);
+    }
+    for (id, hash) in db::unused_recovery_codes(&st.pool, user_id).await? {
+        if auth::verify_password_throttled(hash, code.clone()).await? {
+            return Ok(db::use_recovery_code(&st.pool, &id, user_id, actor).await?);
+        }
+    }
+    Ok(false)
+}
+
+fn db_api_key_to_gql(k: db::DbApiKey) -> ApiKey {
+    ApiKey {
+        id: k.id,
+        name: k.name,
+        key_prefix: k.key_prefix,
+        services: k.services,
+        permissions: k.permissions,
+        created_by: k.created_by,
+        created_at: k.created_at,
+        last_used_at: k.last_used_at,
+        revoked_at: k.revoked_at,
+    }
+}
+
+fn refresh_expires_at() -> i64 {
+    chrono::Utc::now().timestamp() + auth::REFRESH_TOKEN_TTL_SECS
+}
+
 fn db_coupon_to_gql(c: db::DbCoupon) -> Coupon {
+    let remaining_uses = c.remaining_uses();
     Coupon {
         id: c.id,
         code: c.code,
         description: c.description,
         service: c.service,
+        valid_from: c.valid_from,
         expires_at: c.expires_at,
         owner_id: c.owner_id,
         created_at: c.created_at,
+        discount: c.discount.map(|d| Discount {
+            kind: match d.kind {
+                discount::DiscountKind::Percentage => DiscountKind::Percentage,
+                discount::DiscountKind::FixedAmount => DiscountKind::FixedAmount,
+                discount::DiscountKind::FreeShipping => DiscountKind::FreeShipping,
+                discount::DiscountKind::FreeItem => DiscountKind::FreeItem,
+            },
+            value: d.value,
+            currency: d.currency,
+            sku: d.sku,
+            min_order_amount: d.min_order_amount,
+            max_discount_amount: d.max_discount_amount,
+        }),
+        batch_id: c.batch_id,
+        max_redemptions: c.limits.max_redemptions,
+        max_redemptions_per_user: c.limits.max_per_user,
+        redemption_count: c.redemption_count,
+        remaining_uses,
+        max_claims: c.limits.max_claims,
+        claim_count: c.claim_count,
+        deleted_at: c.deleted_at,
+        deleted_by: c.deleted_by,
+    }
+}
+
+fn db_batch_to_gql(b: db::DbCouponBatch) -> CouponBatch {
+    CouponBatch {
+        id: b.id,
+        pattern: b.pattern,
+        count: b.count,
+        description: b.description,
+        service: b.service,
+        valid_from: b.valid_from,
+        expires_at: b.expires_at,
+        created_by: b.created_by,
+        created_at: b.created_at,
+    }
+}
+
+fn gql_discount_to_domain(d: DiscountInput) -> ApiResult<discount::Discount> {
+    discount::Discount {
+        kind: match d.kind {
+            DiscountKind::Percentage => discount::DiscountKind::Percentage,
+            DiscountKind::FixedAmount => discount::DiscountKind::FixedAmount,
+            DiscountKind::FreeShipping => discount::DiscountKind::FreeShipping,
+            DiscountKind::FreeItem => discount::DiscountKind::FreeItem,
+        },
+        value: d.value,
+        currency: d.currency,
+        sku: d.sku,
+        min_order_amount: d.min_order_amount,
+        max_discount_amount: d.max_discount_amount,
     }
+    .validate()
+    .map_err(|e| ApiError::validation(e.to_string()))
 }
@@ -10,12 +10,18 @@ struct GraphQLRequestBody {
     variables: serde_json::Value,
 }
 
-pub async fn login(email: String, password: String) -> Result<String, Box<dyn std::error::Error>> {
+#[derive(Deserialize, Clone)]
+pub struct Tokens {
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+pub async fn login(email: String, password: String) -> Result<Tokens, Box<dyn std::error::Error>> {
     let client = Client::new();
 
     let query = r#"
         mutation Login($email: String!, $password: String!) {
-            login(input: { email: $email, password: $password })
+            login(input: { email: $email, password: $password }) { access_token refresh_token }
         }
     "#;
 
@@ -35,9 +41,36 @@ pub async fn login(email: String, password: String) -> Result<String, Box<dyn st
     // 👇 Log raw response in browser devtools console
     console::log_1(&format!("GraphQL response: {:?}", res).into());
 
-    if let Some(token) = res["data"]["login"].as_str() {
-        Ok(token.to_string())
-    } else {
-        Err(format!("Login failed: {:?}", res).into())
+    match serde_json::from_value::<Tokens>(res["data"]["login"].clone()) {
+        Ok(tokens) => Ok(tokens),
+        Err(_) => Err(format!("Login failed: {:?}", res).into()),
+    }
+}
+
+// Exchange a refresh token for a fresh access/refresh pair (the old refresh token is consumed).
+pub async fn refresh(refresh_token: String) -> Result<Tokens, Box<dyn std::error::Error>> {
+    let client = Client::new();
+
+    let query = r#"
+        mutation Refresh($refreshToken: String!) {
+            refreshToken(refreshToken: $refreshToken) { access_token refresh_token }
+        }
+    "#;
+
+    let body = GraphQLRequestBody {
+        query: query.to_string(),
+        variables: serde_json::json!({ "refreshToken": refresh_token }),
+    };
+
+    let res = client.post(GRAPHQL_ENDPOINT)
+        .json(&body)
+        .send()
+        .await?
+        .json::<serde_json::Value>()
+        .await?;
+
+    match serde_json::from_value::<Tokens>(res["data"]["refreshToken"].clone()) {
+        Ok(tokens) => Ok(tokens),
+        Err(_) => Err(format!("Refresh failed: {:?}", res).into()),
     }
 }
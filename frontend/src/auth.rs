@@ -29,4 +29,18 @@ impl AuthContext {
         }
         self.token.set(token);
     }
+
+    pub fn refresh_token(&self) -> Option<String> {
+        window().unwrap().local_storage().unwrap()
+            .and_then(|s| s.get_item("refresh_token").ok().flatten())
+    }
+
+    pub fn set_refresh_token(&self, token: Option<String>) {
+        if let Some(storage) = window().unwrap().local_storage().unwrap() {
+            match &token {
+                Some(t) => storage.set_item("refresh_token", t).unwrap(),
+                None => storage.remove_item("refresh_token").unwrap(),
+            }
+        }
+    }
 }
@@ -1,6 +1,5 @@
 use leptos::*;
 use leptos_router::*;
-use web_sys::window;
 
 mod api;
 mod auth;
@@ -70,8 +69,9 @@ fn Login() -> impl IntoView {
 
         leptos::spawn_local(async move {
             match api::login(email, password).await {
-                Ok(token) => {
-                    auth.set_token(Some(token.clone()));
+                Ok(tokens) => {
+                    auth.set_token(Some(tokens.access_token));
+                    auth.set_refresh_token(Some(tokens.refresh_token));
                     navigate("/secret", Default::default());
                 }
                 Err(err) => logging::error!("❌ Login failed: {:?}", err),
@@ -105,6 +105,21 @@ fn Login() -> impl IntoView {
 }
 
 
+// Call backend /secret route; `None` means the token was rejected.
+async fn fetch_secret(token: &str) -> Option<String> {
+    let client = reqwest::Client::new();
+    let res = client
+        .get("http://localhost:3000/secret")
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .await
+        .ok()?;
+    if !res.status().is_success() {
+        return None;
+    }
+    Some(res.text().await.unwrap_or_else(|_| "Welcome!".to_string()))
+}
+
 #[component]
 fn SecretPage() -> impl IntoView {
     let auth = use_context::<auth::AuthContext>().unwrap();
@@ -112,30 +127,37 @@ fn SecretPage() -> impl IntoView {
     let (message, set_message) = create_signal("Checking authentication...".to_string());
 
     leptos::spawn_local(async move {
-        if let Some(storage) = window().unwrap().local_storage().unwrap() {
-            if let Ok(Some(token)) = storage.get_item("jwt") {
-                // Call backend /secret route
-                let client = reqwest::Client::new();
-                let res = client
-                    .get("http://localhost:3000/secret")
-                    .header("Authorization", format!("Bearer {}", token))
-                    .send()
-                    .await;
-
-                match res {
-                    Ok(r) if r.status().is_success() => {
-                        let text = r.text().await.unwrap_or_else(|_| "Welcome!".to_string());
-                        set_message.set(format!("✅ {}", text));
+        let Some(token) = auth.token.get_untracked() else {
+            set_message.set("❌ Not logged in. Redirecting...".to_string());
+            navigate("/login", Default::default());
+            return;
+        };
+
+        match fetch_secret(&token).await {
+            Some(text) => set_message.set(format!("✅ {}", text)),
+            None => {
+                // Access token expired: try once to renew it with the refresh token.
+                let renewed = match auth.refresh_token() {
+                    Some(rt) => api::refresh(rt).await.ok(),
+                    None => None,
+                };
+                let text = match renewed {
+                    Some(tokens) => {
+                        auth.set_token(Some(tokens.access_token.clone()));
+                        auth.set_refresh_token(Some(tokens.refresh_token));
+                        fetch_secret(&tokens.access_token).await
                     }
-                    _ => {
-                        storage.remove_item("jwt").unwrap();
+                    None => None,
+                };
+                match text {
+                    Some(text) => set_message.set(format!("✅ {}", text)),
+                    None => {
+                        auth.set_token(None);
+                        auth.set_refresh_token(None);
                         set_message.set("❌ Session expired. Redirecting...".to_string());
                         navigate("/login", Default::default());
                     }
                 }
-            } else {
-                set_message.set("❌ Not logged in. Redirecting...".to_string());
-                navigate("/login", Default::default());
             }
         }
     });